@@ -1,6 +1,25 @@
+#!/bin/sh
 # env "CC_wasm32-wasi=${WASI_CC}" \
 # CFLAGS="-DSQLITE_OS_OTHER --sysroot=$WASI_SDK_PATH/share/wasi-sysroot" \
 # AR="$WASI_SDK_PATH/bin/ar" \
 # CARGO_TARGET_WASM32_WASI_LINKER=$WASI_SDK_PATH/bin/lld \
 # RUST_LOG=lunatic=debug cargo run --target=wasm32-wasi
 
+# The lunatic runtime does not forward signals to the broker. SIGTERM and
+# SIGINT are translated into a shutdown request file that the broker checks for
+SHUTDOWN_REQUEST=persistence/shutdown.request
+
+cargo build --release || exit 1
+lunatic target/wasm32-wasi/release/mqtt_broker.wasm &
+broker=$!
+
+shutdown() {
+    # the broker is stopped right away if the request cannot be created
+    mkdir -p "$(dirname "$SHUTDOWN_REQUEST")" && touch "$SHUTDOWN_REQUEST" || kill "$broker"
+}
+trap shutdown TERM INT
+
+# a trapped signal interrupts `wait`, keep waiting until the broker exited
+while kill -0 "$broker" 2> /dev/null; do
+    wait "$broker"
+done
@@ -0,0 +1,355 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+/// how often the acl file is checked for modifications
+const RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Who a rule applies to
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Principal {
+    Any,
+    /// the username a client connected with. Plain CONNECT passwords are not
+    /// checked, so without SCRAM-SHA-256 the client asserts it itself
+    User(String),
+    ClientId(String),
+}
+
+/// Which operations a rule applies to
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Access {
+    Publish,
+    Subscribe,
+    Both,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Permission {
+    Allow,
+    Deny,
+}
+
+/// A single entry of the access control list. The topic may contain the
+/// MQTT wildcards `+` and `#` as well as `%u` (username) and `%c` (client id)
+/// which are substituted before matching.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AclRule {
+    pub principal: Principal,
+    pub access: Access,
+    pub topic: String,
+    pub permission: Permission,
+}
+
+/// Content of the acl file. Rules are evaluated in order and the first
+/// matching rule decides, if no rule matches the `default` is used.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AclConfig {
+    pub default: Permission,
+    pub rules: Vec<AclRule>,
+}
+
+impl Default for AclConfig {
+    fn default() -> Self {
+        AclConfig {
+            default: Permission::Allow,
+            rules: vec![],
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct AclEngine {
+    path: Option<PathBuf>,
+    modified: Option<SystemTime>,
+    last_checked: SystemTime,
+    config: AclConfig,
+    /// root of the per client response topics, see `response_root`
+    response_root: Option<String>,
+}
+
+impl AclEngine {
+    /// create an engine from the given file, without a file every operation is allowed
+    pub fn new(path: Option<String>) -> AclEngine {
+        let mut engine = AclEngine {
+            path: path.map(PathBuf::from),
+            modified: None,
+            last_checked: SystemTime::now(),
+            config: AclConfig::default(),
+            response_root: None,
+        };
+        engine.reload();
+        engine
+    }
+
+    pub fn from_config(config: AclConfig) -> AclEngine {
+        AclEngine {
+            path: None,
+            modified: None,
+            last_checked: SystemTime::now(),
+            config,
+            response_root: None,
+        }
+    }
+
+    /// scope the topics below `<root>/<client id>` to their client. Only the
+    /// client itself may subscribe to them, before any rule of the file is checked
+    pub fn response_root(mut self, root: String) -> AclEngine {
+        self.response_root = Some(root);
+        self
+    }
+
+    /// re-read the acl file if it was modified since it was loaded last.
+    /// The file is checked at most once every `RELOAD_INTERVAL`
+    pub fn reload_if_changed(&mut self) {
+        match self.last_checked.elapsed() {
+            Ok(elapsed) if elapsed < RELOAD_INTERVAL => return,
+            _ => self.last_checked = SystemTime::now(),
+        }
+        let modified = self
+            .path
+            .as_ref()
+            .and_then(|path| fs::metadata(path).ok())
+            .and_then(|meta| meta.modified().ok());
+        if modified.is_some() && modified != self.modified {
+            self.reload();
+        }
+    }
+
+    fn reload(&mut self) {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return,
+        };
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                lunatic_log::error!("[ACL] Failed to read acl file {:?} | {:?}", path, e);
+                return;
+            }
+        };
+        // keep the previous rules if the file is broken
+        match ron::from_str::<AclConfig>(&contents) {
+            Ok(config) => {
+                lunatic_log::info!("[ACL] Loaded {} rules from {:?}", config.rules.len(), path);
+                self.config = config;
+                self.modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+            }
+            Err(e) => {
+                lunatic_log::error!("[ACL] Failed to parse acl file {:?} | {}", path, e);
+            }
+        }
+    }
+
+    pub fn can_publish(&self, username: Option<&str>, client_id: &str, topic: &str) -> bool {
+        self.check(Access::Publish, username, client_id, topic)
+    }
+
+    pub fn can_subscribe(&self, username: Option<&str>, client_id: &str, filter: &str) -> bool {
+        self.check(Access::Subscribe, username, client_id, filter)
+    }
+
+    fn check(&self, access: Access, username: Option<&str>, client_id: &str, topic: &str) -> bool {
+        if let Some(root) = &self.response_root {
+            if access == Access::Subscribe && topic.split('/').next() == Some(root.as_str()) {
+                // a client id with wildcards would widen the scope to other clients
+                return is_literal_level(client_id)
+                    && pattern_matches(&format!("{}/{}/#", root, client_id), topic);
+            }
+        }
+        for rule in self.config.rules.iter() {
+            if rule.access != Access::Both && rule.access != access {
+                continue;
+            }
+            let applies = match &rule.principal {
+                Principal::Any => true,
+                Principal::User(user) => username == Some(user.as_str()),
+                Principal::ClientId(id) => id == client_id,
+            };
+            if !applies {
+                continue;
+            }
+            // a username or client id with levels or wildcards would widen the rule
+            let widens = |value: &str| !is_literal_level(value);
+            if (rule.topic.contains("%c") && widens(client_id))
+                || (rule.topic.contains("%u") && username.is_some_and(widens))
+            {
+                lunatic_log::info!(
+                    "[ACL] Denied {:?} of client {:?} by rule {:?}, its id cannot be substituted",
+                    access,
+                    client_id,
+                    rule.topic
+                );
+                return false;
+            }
+            let pattern = match substitute(&rule.topic, username, client_id) {
+                Some(pattern) => pattern,
+                None => continue,
+            };
+            if pattern_matches(&pattern, topic) {
+                return rule.permission == Permission::Allow;
+            }
+        }
+        self.config.default == Permission::Allow
+    }
+}
+
+/// whether a username or client id can be used as a single topic level
+pub fn is_literal_level(value: &str) -> bool {
+    !value.contains(&['/', '+', '#'][..])
+}
+
+/// replace `%u` and `%c` in a rule topic. Rules that reference the username
+/// never apply to clients that did not send one.
+fn substitute(topic: &str, username: Option<&str>, client_id: &str) -> Option<String> {
+    let topic = topic.replace("%c", client_id);
+    if topic.contains("%u") {
+        return username.map(|user| topic.replace("%u", user));
+    }
+    Some(topic)
+}
+
+/// check if the acl pattern covers the given topic name or topic filter.
+/// A filter is only covered if every topic it can match is covered as well,
+/// so `+` in a filter needs a `+` or `#` in the pattern.
+pub fn pattern_matches(pattern: &str, topic: &str) -> bool {
+    let mut pattern_levels = pattern.split('/');
+    let mut topic_levels = topic.split('/');
+    loop {
+        match (pattern_levels.next(), topic_levels.next()) {
+            (Some("#"), _) => return true,
+            (Some("+"), Some(level)) => {
+                if level == "#" {
+                    return false;
+                }
+            }
+            (Some(expected), Some(level)) => {
+                if expected != level {
+                    return false;
+                }
+            }
+            (None, None) => return true,
+            _ => return false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn engine(rules: Vec<AclRule>) -> AclEngine {
+        AclEngine::from_config(AclConfig {
+            default: Permission::Deny,
+            rules,
+        })
+    }
+
+    #[test]
+    fn pattern_matching() {
+        assert!(pattern_matches("finance/#", "finance"));
+        assert!(pattern_matches("finance/#", "finance/stocks/apple"));
+        assert!(pattern_matches("finance/+", "finance/stocks"));
+        assert!(pattern_matches("finance/+", "finance/+"));
+        assert!(!pattern_matches("finance/+", "finance/#"));
+        assert!(!pattern_matches("finance/stocks", "finance/+"));
+        assert!(!pattern_matches("finance/+", "finance/stocks/apple"));
+        assert!(!pattern_matches("finance", "finances"));
+    }
+
+    #[test]
+    fn substitutions() {
+        let acl = engine(vec![
+            AclRule {
+                principal: Principal::Any,
+                access: Access::Both,
+                topic: "users/%u/#".to_owned(),
+                permission: Permission::Allow,
+            },
+            AclRule {
+                principal: Principal::Any,
+                access: Access::Subscribe,
+                topic: "clients/%c/+".to_owned(),
+                permission: Permission::Allow,
+            },
+        ]);
+        assert!(acl.can_publish(Some("alice"), "c1", "users/alice/status"));
+        assert!(!acl.can_publish(Some("bob"), "c1", "users/alice/status"));
+        assert!(!acl.can_publish(None, "c1", "users/alice/status"));
+        assert!(acl.can_subscribe(None, "c1", "clients/c1/inbox"));
+        assert!(!acl.can_subscribe(None, "c2", "clients/c1/inbox"));
+        assert!(!acl.can_publish(None, "c1", "clients/c1/inbox"));
+    }
+
+    #[test]
+    fn substitutions_with_wildcards() {
+        let acl = engine(vec![
+            AclRule {
+                principal: Principal::Any,
+                access: Access::Both,
+                topic: "users/%u/#".to_owned(),
+                permission: Permission::Allow,
+            },
+            AclRule {
+                principal: Principal::Any,
+                access: Access::Subscribe,
+                topic: "clients/%c/+".to_owned(),
+                permission: Permission::Allow,
+            },
+        ]);
+        assert!(!acl.can_subscribe(Some("#"), "c1", "users/alice/status"));
+        assert!(!acl.can_subscribe(Some("+"), "c1", "users/alice/status"));
+        assert!(!acl.can_publish(Some("alice/status"), "c1", "users/alice/status/x"));
+        assert!(!acl.can_subscribe(None, "#", "clients/c1/inbox"));
+        assert!(!acl.can_subscribe(None, "c1/inbox", "clients/c1/inbox/x"));
+        // the rules still apply to everyone else
+        assert!(acl.can_subscribe(Some("alice"), "c1", "users/alice/status"));
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let acl = engine(vec![
+            AclRule {
+                principal: Principal::ClientId("sensor".to_owned()),
+                access: Access::Publish,
+                topic: "telemetry/secret".to_owned(),
+                permission: Permission::Deny,
+            },
+            AclRule {
+                principal: Principal::User("device".to_owned()),
+                access: Access::Publish,
+                topic: "telemetry/#".to_owned(),
+                permission: Permission::Allow,
+            },
+        ]);
+        assert!(acl.can_publish(Some("device"), "sensor", "telemetry/temp"));
+        assert!(!acl.can_publish(Some("device"), "sensor", "telemetry/secret"));
+        assert!(acl.can_publish(Some("device"), "other", "telemetry/secret"));
+        assert!(!acl.can_subscribe(Some("device"), "sensor", "telemetry/temp"));
+    }
+
+    #[test]
+    fn response_topics() {
+        let acl = engine(vec![AclRule {
+            principal: Principal::Any,
+            access: Access::Both,
+            topic: "#".to_owned(),
+            permission: Permission::Allow,
+        }])
+        .response_root("responses".to_owned());
+        assert!(acl.can_subscribe(None, "c1", "responses/c1"));
+        assert!(acl.can_subscribe(None, "c1", "responses/c1/rpc/+"));
+        assert!(!acl.can_subscribe(None, "c2", "responses/c1/rpc/+"));
+        assert!(!acl.can_subscribe(None, "c1", "responses/+/rpc"));
+        assert!(!acl.can_subscribe(None, "c1", "responses/#"));
+        assert!(acl.can_publish(None, "c2", "responses/c1/rpc"));
+        // client ids that are not a single literal level have no response topics
+        assert!(!acl.can_subscribe(None, "#", "responses/c1/rpc"));
+        assert!(!acl.can_subscribe(None, "+", "responses/+/rpc"));
+        assert!(!acl.can_subscribe(None, "c1/rpc", "responses/c1/rpc/#"));
+
+        let acl = engine(vec![]).response_root("responses".to_owned());
+        assert!(acl.can_subscribe(None, "c1", "responses/c1/#"));
+        assert!(!acl.can_subscribe(None, "c1", "telemetry"));
+    }
+}
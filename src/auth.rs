@@ -0,0 +1,65 @@
+//! Enhanced authentication of MQTT v5. The client names a method in its
+//! CONNECT and both sides exchange AUTH packets until the method succeeds
+//! or fails. The same exchange is used when a client re-authenticates
+use crate::scram::{self, ScramSha256};
+use mqtt_packet_3_5::{AuthPacket, AuthProperties, MqttPacket};
+
+/// the outcome of one step of an authentication method
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuthStep {
+    /// send the data to the client and wait for its answer
+    Continue(Vec<u8>),
+    /// the client is authenticated as `username`, the optional data is
+    /// sent along with the CONNACK or the final AUTH packet
+    Success {
+        username: String,
+        data: Option<Vec<u8>>,
+    },
+    /// the client is not authorized, the string explains why
+    Failure(String),
+}
+
+/// A challenge/response authentication method. A new authenticator is
+/// created for every exchange and fed with the data the client sends
+pub trait Authenticator {
+    fn step(&mut self, data: &[u8]) -> AuthStep;
+}
+
+/// the authenticator of a method, `None` if the broker does not support it.
+/// SCRAM-SHA-256 needs the credentials file of the config
+pub fn authenticator(
+    method: &str,
+    credentials_file: Option<&str>,
+) -> Option<Box<dyn Authenticator>> {
+    match method {
+        scram::METHOD => {
+            let file = credentials_file?;
+            Some(Box::new(ScramSha256::new(scram::load_credentials(file))))
+        }
+        _ => None,
+    }
+}
+
+/// an AUTH packet of an exchange with the given method
+pub fn auth_packet(reason_code: u8, method: &str, data: Option<Vec<u8>>) -> MqttPacket {
+    MqttPacket::Auth(AuthPacket {
+        reason_code,
+        properties: Some(AuthProperties {
+            authentication_method: Some(method.to_owned()),
+            authentication_data: data,
+            reason_string: None,
+            user_properties: None,
+        }),
+    })
+}
+
+/// the method and data of an AUTH packet
+pub fn method_and_data(packet: &AuthPacket) -> (Option<&str>, Vec<u8>) {
+    match &packet.properties {
+        Some(properties) => (
+            properties.authentication_method.as_deref(),
+            properties.authentication_data.clone().unwrap_or_default(),
+        ),
+        None => (None, Vec::new()),
+    }
+}
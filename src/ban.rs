@@ -0,0 +1,365 @@
+use crate::config::BanConfig;
+use lunatic::{abstract_process, host, process::ProcessRef, supervisor::Supervisor, Tag};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// The `BanSup` is supervising one global instance of the `BanProcess`.
+pub struct BanSup;
+impl Supervisor for BanSup {
+    type Arg = (String, BanConfig);
+    type Children = BanProcess;
+
+    fn init(config: &mut lunatic::supervisor::SupervisorConfig<Self>, (name, ban): Self::Arg) {
+        // Always register the `BanProcess` under the name passed to the supervisor.
+        config.children_args((ban, Some(name)))
+    }
+}
+
+/// A peer is tracked by the address it connects from and by the client id it uses
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Peer {
+    Ip(IpAddr),
+    ClientId(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Offense {
+    Connect,
+    MalformedPacket,
+    FailedAuth,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Ban {
+    pub peer: Peer,
+    pub reason: Offense,
+    pub banned_at: SystemTime,
+    pub until: SystemTime,
+    /// number of consecutive bans, every ban lasts twice as long as the previous one
+    pub strikes: u32,
+}
+
+/// Counts offenses within a sliding window and bans peers that cross the
+/// threshold of the configuration
+#[derive(Debug)]
+pub struct BanList {
+    config: BanConfig,
+    attempts: HashMap<(Peer, Offense), VecDeque<SystemTime>>,
+    bans: HashMap<Peer, Ban>,
+}
+
+impl BanList {
+    pub fn new(config: BanConfig, bans: Vec<Ban>) -> BanList {
+        BanList {
+            config,
+            attempts: HashMap::new(),
+            bans: bans
+                .into_iter()
+                .map(|ban| (ban.peer.clone(), ban))
+                .collect(),
+        }
+    }
+
+    pub fn is_banned(&self, peer: &Peer, now: SystemTime) -> bool {
+        self.bans.get(peer).is_some_and(|ban| ban.until > now)
+    }
+
+    /// record an offense of the peer, returns true if it got banned because of it
+    pub fn record(&mut self, peer: Peer, offense: Offense, now: SystemTime) -> bool {
+        if self.is_banned(&peer, now) {
+            return false;
+        }
+        let window = Duration::from_secs(self.config.window_secs);
+        let key = (peer, offense);
+        let attempts = self.attempts.entry(key.clone()).or_default();
+        attempts.push_back(now);
+        while let Some(first) = attempts.front() {
+            match now.duration_since(*first) {
+                Ok(elapsed) if elapsed >= window => attempts.pop_front(),
+                _ => break,
+            };
+        }
+        if attempts.len() as u32 <= self.threshold(offense) {
+            return false;
+        }
+
+        self.attempts.remove(&key);
+        let (peer, _) = key;
+        // strikes are forgotten once the peer behaved for `max_ban_secs`
+        let forget_after = Duration::from_secs(self.config.max_ban_secs);
+        let strikes = match self.bans.get(&peer) {
+            Some(previous) => match now.duration_since(previous.until) {
+                Ok(elapsed) if elapsed >= forget_after => 1,
+                _ => previous.strikes + 1,
+            },
+            None => 1,
+        };
+        let until = now + self.ban_duration(strikes);
+        self.bans.insert(
+            peer.clone(),
+            Ban {
+                peer,
+                reason: offense,
+                banned_at: now,
+                until,
+                strikes,
+            },
+        );
+        true
+    }
+
+    fn threshold(&self, offense: Offense) -> u32 {
+        match offense {
+            Offense::Connect => self.config.max_connects,
+            Offense::MalformedPacket => self.config.max_malformed_packets,
+            Offense::FailedAuth => self.config.max_failed_auth,
+        }
+    }
+
+    /// exponential backoff, capped at `max_ban_secs`
+    pub fn ban_duration(&self, strikes: u32) -> Duration {
+        let factor = 2u64.saturating_pow(strikes.saturating_sub(1));
+        Duration::from_secs(
+            self.config
+                .base_ban_secs
+                .saturating_mul(factor)
+                .min(self.config.max_ban_secs),
+        )
+    }
+
+    /// bans that are currently in effect
+    pub fn active(&self, now: SystemTime) -> Vec<Ban> {
+        self.bans
+            .values()
+            .filter(|ban| ban.until > now)
+            .cloned()
+            .collect()
+    }
+
+    /// all bans including expired ones whose strikes are still remembered
+    pub fn bans(&self) -> Vec<Ban> {
+        self.bans.values().cloned().collect()
+    }
+
+    /// lift the ban of a single peer or of everyone, returns the number of lifted bans
+    pub fn clear(&mut self, peer: Option<&Peer>, now: SystemTime) -> usize {
+        let before = self.active(now).len();
+        match peer {
+            Some(peer) => {
+                self.bans.remove(peer);
+                self.attempts.retain(|(p, _), _| p != peer);
+            }
+            None => {
+                self.bans.clear();
+                self.attempts.clear();
+            }
+        }
+        before - self.active(now).len()
+    }
+
+    /// drop attempts that left the window and bans that are forgotten
+    pub fn prune(&mut self, now: SystemTime) {
+        let window = Duration::from_secs(self.config.window_secs);
+        let forget_after = Duration::from_secs(self.config.max_ban_secs);
+        self.attempts.retain(|_, attempts| {
+            attempts
+                .back()
+                .is_some_and(|last| now.duration_since(*last).map_or(true, |e| e < window))
+        });
+        self.bans.retain(|_, ban| {
+            now.duration_since(ban.until)
+                .map_or(true, |e| e < forget_after)
+        });
+    }
+}
+
+pub struct BanProcess {
+    enabled: bool,
+    file: String,
+    list: BanList,
+    last_pruned: SystemTime,
+}
+
+#[abstract_process(visibility = pub)]
+impl BanProcess {
+    /// function that retrieves the running ban process
+    pub fn get_process() -> ProcessRef<BanProcess> {
+        ProcessRef::<BanProcess>::lookup("bans").unwrap()
+    }
+
+    #[init]
+    fn init(_: ProcessRef<Self>, config: BanConfig) -> Self {
+        // Listeners and clients shouldn't take down the ban process. This makes the link one-directional.
+        unsafe { host::api::process::die_when_link_dies(0) };
+
+        let bans = match fs::read_to_string(&config.file) {
+            Ok(contents) => ron::from_str::<Vec<Ban>>(&contents).unwrap_or_else(|e| {
+                lunatic_log::error!("[Bans] Failed to parse {} | {}", config.file, e);
+                vec![]
+            }),
+            Err(_) => vec![],
+        };
+        lunatic_log::info!("[Bans] Loaded {} bans from {}", bans.len(), config.file);
+        BanProcess {
+            enabled: config.enabled,
+            file: config.file.clone(),
+            list: BanList::new(config, bans),
+            last_pruned: SystemTime::now(),
+        }
+    }
+
+    #[terminate]
+    fn terminate(self) {
+        lunatic_log::info!("Shutdown process");
+    }
+
+    #[handle_link_trapped]
+    fn handle_link_trapped(&self, _tag: Tag) {
+        lunatic_log::error!("Link trapped");
+    }
+
+    /// write all bans to disk, the file is replaced atomically
+    fn save(&self) {
+        if let Some(dir) = Path::new(&self.file).parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+        let tmp = format!("{}.tmp", self.file);
+        let result = ron::to_string(&self.list.bans())
+            .map_err(|e| e.to_string())
+            .and_then(|contents| fs::write(&tmp, contents).map_err(|e| e.to_string()))
+            .and_then(|_| fs::rename(&tmp, &self.file).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            lunatic_log::error!("[Bans] Failed to write {} | {}", self.file, e);
+        }
+    }
+
+    fn record(&mut self, peer: Peer, offense: Offense) {
+        let now = SystemTime::now();
+        if self.last_pruned.elapsed().unwrap_or_default()
+            >= Duration::from_secs(self.list.config.window_secs)
+        {
+            self.list.prune(now);
+            self.last_pruned = now;
+        }
+        if self.list.record(peer.clone(), offense, now) {
+            lunatic_log::info!("[Bans] Banned {:?} because of {:?}", peer, offense);
+            self.save();
+        }
+    }
+
+    // =======================
+    // Request handlers
+    // =======================
+    /// count a connection attempt, returns false if the peer is banned
+    #[handle_request]
+    pub fn connect_attempt(&mut self, peer: Peer) -> bool {
+        if !self.enabled {
+            return true;
+        }
+        self.record(peer.clone(), Offense::Connect);
+        !self.list.is_banned(&peer, SystemTime::now())
+    }
+
+    #[handle_request]
+    pub fn list_bans(&self) -> Vec<Ban> {
+        self.list.active(SystemTime::now())
+    }
+
+    /// lift the ban of a single peer or of everyone
+    #[handle_request]
+    pub fn clear_bans(&mut self, peer: Option<Peer>) -> usize {
+        let cleared = self.list.clear(peer.as_ref(), SystemTime::now());
+        lunatic_log::info!("[Bans] Cleared {} bans", cleared);
+        self.save();
+        cleared
+    }
+
+    // =======================
+    // Message handlers
+    // =======================
+    #[handle_message]
+    pub fn track_offense(&mut self, peer: Peer, offense: Offense) {
+        if self.enabled {
+            self.record(peer, offense);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list() -> BanList {
+        BanList::new(
+            BanConfig {
+                window_secs: 10,
+                max_connects: 3,
+                max_malformed_packets: 1,
+                base_ban_secs: 60,
+                max_ban_secs: 600,
+                ..Default::default()
+            },
+            vec![],
+        )
+    }
+
+    fn at(secs: u64) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn sliding_window() {
+        let mut bans = list();
+        let peer = Peer::ClientId("flood".to_owned());
+        for secs in [0, 4, 8] {
+            assert!(!bans.record(peer.clone(), Offense::Connect, at(secs)));
+        }
+        // the first attempt left the window
+        assert!(!bans.record(peer.clone(), Offense::Connect, at(11)));
+        assert!(bans.record(peer.clone(), Offense::Connect, at(12)));
+        assert!(bans.is_banned(&peer, at(12)));
+        assert!(!bans.is_banned(&peer, at(72)));
+    }
+
+    #[test]
+    fn exponential_backoff() {
+        let bans = list();
+        assert_eq!(bans.ban_duration(1), Duration::from_secs(60));
+        assert_eq!(bans.ban_duration(2), Duration::from_secs(120));
+        assert_eq!(bans.ban_duration(4), Duration::from_secs(480));
+        assert_eq!(bans.ban_duration(5), Duration::from_secs(600));
+        assert_eq!(bans.ban_duration(100), Duration::from_secs(600));
+
+        let mut bans = list();
+        let peer = Peer::Ip("10.0.0.1".parse().unwrap());
+        bans.record(peer.clone(), Offense::MalformedPacket, at(0));
+        assert!(bans.record(peer.clone(), Offense::MalformedPacket, at(0)));
+        bans.record(peer.clone(), Offense::MalformedPacket, at(60));
+        assert!(bans.record(peer.clone(), Offense::MalformedPacket, at(60)));
+        assert_eq!(bans.active(at(60))[0].until, at(180));
+        // strikes are forgotten after max_ban_secs without offense
+        bans.record(peer.clone(), Offense::MalformedPacket, at(780));
+        assert!(bans.record(peer.clone(), Offense::MalformedPacket, at(780)));
+        assert_eq!(bans.active(at(780))[0].strikes, 1);
+    }
+
+    #[test]
+    fn clear_bans() {
+        let mut bans = list();
+        let first = Peer::ClientId("first".to_owned());
+        let second = Peer::ClientId("second".to_owned());
+        for peer in [&first, &second] {
+            bans.record(peer.clone(), Offense::MalformedPacket, at(0));
+            bans.record(peer.clone(), Offense::MalformedPacket, at(0));
+        }
+        assert_eq!(bans.clear(Some(&first), at(1)), 1);
+        assert!(!bans.is_banned(&first, at(1)));
+        assert!(bans.is_banned(&second, at(1)));
+        assert_eq!(bans.clear(None, at(1)), 1);
+        assert!(bans.active(at(1)).is_empty());
+    }
+}
@@ -1,10 +1,24 @@
+use crate::auth::{self, AuthStep, Authenticator};
+use crate::ban::{BanProcess, BanProcessHandler, Offense, Peer};
+use crate::config::ConnectionConfig;
+use crate::connect::{self, ConnectRefusal, RECEIVE_MAXIMUM};
+use crate::framing::{self, FrameError};
+use crate::reason_code;
+use crate::stream::{ClientStream, Connection};
 use crate::structure::WriterRef;
+use crate::topic_alias::{InboundAliases, OutboundAliases, TOPIC_ALIAS_MAXIMUM};
+use crate::topic_tree::validate_topic_name;
+use crate::websocket::OPCODE_CLOSE;
 use lunatic::process::{AbstractProcess, ProcessRef, StartProcess};
 use lunatic::{abstract_process, Tag};
-use lunatic::{net::TcpStream, Mailbox, Process};
-use mqtt_packet_3_5::{ConnackPacket, ConnectPacket, MqttPacket, PacketDecoder};
-use std::io::Write;
-use std::time::SystemTime;
+use lunatic::{Mailbox, Process};
+use mqtt_packet_3_5::{
+    ConfirmationPacket, ConnackPacket, ConnackProperties, ConnectPacket, DisconnectPacket,
+    DisconnectProperties, MqttPacket, PubackPubrecCode, PublishPacket, PublishProperties,
+};
+use std::collections::HashSet;
+use std::io::{ErrorKind, Write};
+use std::time::{Duration, SystemTime};
 use uuid::Uuid;
 
 use crate::coordinator::{CoordinatorProcess, CoordinatorProcessHandler};
@@ -14,137 +28,710 @@ pub struct ClientProcess {
     // coordinator: ProcessRef<CoordinatorProcess>,
     // writer: WriterRef,
     // connect_packet: ConnectPacket,
-    // // pub reader: PacketDecoder<TcpStream>,
+    // // pub reader: PacketDecoder<ClientStream>,
     // // protocol_version: u8,
     // is_v5: bool,
     // client_id: String,
 }
 
 impl AbstractProcess for ClientProcess {
-    type Arg = TcpStream;
+    type Arg = Connection;
     type State = Self;
 
-    fn init(this: ProcessRef<Self>, stream: Self::Arg) -> Self::State {
+    fn init(this: ProcessRef<Self>, connection: Self::Arg) -> Self::State {
         // Look up the coordinator or fail if it doesn't exist.
         let coordinator = ProcessRef::<CoordinatorProcess>::lookup("coordinator").unwrap();
         // Link coordinator to child. The coordinator sets `die_when_link_dies` to `0` and will not fail if child fails.
         coordinator.link();
 
-        let connect_packet = match PacketDecoder::from_stream(stream.clone()).decode_packet(3) {
-            Ok(MqttPacket::Connect(packet)) => packet,
-            x => {
-                lunatic_log::error!("Unexpected value instead of connect_packet {:?}", x);
-                panic!("Invalid connect packet");
+        // the reader owns the connection, once it is closed the client stops as well
+        Process::spawn_link(
+            (this, connection, coordinator),
+            |(this, connection, coordinator), _: Mailbox<()>| {
+                handle_connection(connection, this.clone(), coordinator);
+                this.shutdown();
+            },
+        );
+
+        ClientProcess {
+            // this,
+            // coordinator,
+            // writer: writer_ref,
+            // connect_packet,
+            // is_v5,
+            // client_id,
+        }
+    }
+}
+
+/// validate the CONNECT, register the client with the coordinator and read
+/// packets until the connection is closed. Protocol violations are answered
+/// with a CONNACK or DISCONNECT before the connection is closed
+fn handle_connection(
+    Connection {
+        mut stream,
+        peer,
+        config,
+    }: Connection,
+    this: ProcessRef<ClientProcess>,
+    coordinator: ProcessRef<CoordinatorProcess>,
+) {
+    if let ClientStream::WebSocket(ws) = &mut stream {
+        if let Err(e) = ws.handshake() {
+            lunatic_log::error!("[Client] WebSocket handshake failed {:?}", e);
+            return;
+        }
+    }
+    lunatic_log::debug!("[Client] Accepted {} connection", stream.transport());
+    let bans = BanProcess::get_process();
+
+    let mut connect_packet = match read_connect(&mut stream, config.max_packet_size) {
+        Ok(Some(packet)) => packet,
+        Ok(None) => return,
+        Err((protocol_version, refusal)) => {
+            if matches!(
+                refusal,
+                ConnectRefusal::MalformedPacket
+                    | ConnectRefusal::ProtocolError
+                    | ConnectRefusal::PacketTooLarge
+            ) {
+                bans.track_offense(Peer::Ip(peer), Offense::MalformedPacket);
+            }
+            refuse(&mut stream, protocol_version, refusal);
+            return;
+        }
+    };
+    let is_v5 = connect_packet.protocol_version == 5;
+    if !connect_packet.client_id.is_empty()
+        && !bans.connect_attempt(Peer::ClientId(connect_packet.client_id.clone()))
+    {
+        refuse(
+            &mut stream,
+            connect_packet.protocol_version,
+            ConnectRefusal::Banned,
+        );
+        return;
+    }
+
+    // enhanced authentication of v5 clients finishes before the session is created
+    let auth_method = connect_packet
+        .properties
+        .as_ref()
+        .and_then(|properties| properties.authentication_method.clone());
+    let mut auth_data = None;
+    if let Some(method) = &auth_method {
+        match authenticate(&mut stream, &config, &connect_packet, method) {
+            Ok((username, data)) => {
+                lunatic_log::info!("[Client] Authenticated {} with {}", username, method);
+                connect_packet.username = Some(username);
+                auth_data = data;
+            }
+            Err(refusal) => {
+                if refusal == ConnectRefusal::NotAuthorized {
+                    bans.track_offense(Peer::Ip(peer), Offense::FailedAuth);
+                    if !connect_packet.client_id.is_empty() {
+                        bans.track_offense(
+                            Peer::ClientId(connect_packet.client_id.clone()),
+                            Offense::FailedAuth,
+                        );
+                    }
+                } else if refusal != ConnectRefusal::BadAuthenticationMethod {
+                    bans.track_offense(Peer::Ip(peer), Offense::MalformedPacket);
+                }
+                refuse(&mut stream, connect_packet.protocol_version, refusal);
+                return;
             }
+        }
+    }
+
+    // clients without an id get a unique one from the broker. v3 clients can
+    // only use this for clean sessions because they cannot learn the assigned id
+    let mut assigned_client_id = None;
+    if connect_packet.client_id.is_empty() {
+        let client_id = format!("auto-{}", Uuid::new_v4().simple());
+        lunatic_log::debug!("[Client] Assigned client id {}", client_id);
+        connect_packet.client_id = client_id.clone();
+        assigned_client_id = Some(client_id);
+    }
+
+    // v5 clients decide the lifetime of their session, v3 clients only ask
+    // whether it should outlive the connection
+    let session_expiry_interval = if is_v5 {
+        Some(
+            connect_packet
+                .properties
+                .as_ref()
+                .and_then(|properties| properties.session_expiry_interval)
+                .unwrap_or(0),
+        )
+    } else {
+        None
+    };
+
+    let writer = WriterProcess::start((stream.clone(), connect_packet.clone()), None);
+    if let ClientStream::WebSocket(ws) = &mut stream {
+        ws.attach_writer(writer.clone());
+    }
+    // Let the coordinator know that we joined.
+    let writer_ref = WriterRef {
+        process: Some(writer.clone()),
+        client_id: connect_packet.client_id.clone(),
+        username: connect_packet.username.clone(),
+        protocol_version: connect_packet.protocol_version,
+        keep_alive: connect_packet.keep_alive,
+        session_id: Uuid::new_v4(),
+        is_persistent_session: session_expiry_interval
+            .map_or(!connect_packet.clean_session, |interval| interval > 0),
+        // v3 clients have no way of limiting the messages in flight
+        receive_maximum: connect_packet
+            .properties
+            .as_ref()
+            .and_then(|properties| properties.receive_maximum)
+            .unwrap_or(u16::MAX),
+        maximum_packet_size: connect_packet
+            .properties
+            .as_ref()
+            .and_then(|properties| properties.maximum_packet_size),
+    };
+    let session_present = coordinator.connect(
+        this,
+        writer_ref.clone(),
+        connect_packet.clean_session,
+        session_expiry_interval,
+    );
+
+    // send connack response to client
+    writer.write_packet(MqttPacket::Connack(ConnackPacket {
+        properties: if is_v5 {
+            Some(ConnackProperties {
+                authentication_method: auth_method.clone(),
+                authentication_data: auth_data,
+                response_information: response_information(
+                    &connect_packet,
+                    &config.response_topic_root,
+                ),
+                ..connect::connack_properties(
+                    &connect_packet,
+                    assigned_client_id,
+                    config.max_packet_size,
+                )
+            })
+        } else {
+            None
+        },
+        reason_code: if is_v5 { Some(0) } else { None },
+        return_code: if !is_v5 { Some(0) } else { None },
+        session_present,
+    }));
+
+    // v3 has no DISCONNECT from the server, the connection is just closed
+    if let Some(reason_code) = read_packets(
+        &mut stream,
+        &config,
+        connect_packet.keep_alive,
+        &writer_ref,
+        auth_method.as_deref(),
+        &coordinator,
+    ) {
+        let offense = match reason_code {
+            reason_code::KEEP_ALIVE_TIMEOUT => None,
+            reason_code::NOT_AUTHORIZED_V5 => Some(Offense::FailedAuth),
+            _ => Some(Offense::MalformedPacket),
         };
+        if let Some(offense) = offense {
+            bans.track_offense(Peer::Ip(peer), offense);
+            bans.track_offense(Peer::ClientId(writer_ref.client_id.clone()), offense);
+        }
+        writer.disconnect(
+            reason_code,
+            reason_code::description(reason_code).map(str::to_owned),
+        );
+    }
+    // the coordinator stops the writer once no job of the worker refers to it
+    coordinator.disconnect(writer_ref);
+}
+
+fn refuse(stream: &mut ClientStream, protocol_version: u8, refusal: ConnectRefusal) {
+    lunatic_log::info!("[Client] Refusing connection {:?}", refusal);
+    if let Some(connack) = refusal.connack(protocol_version) {
+        let _ = stream.write_all(&connack);
+    }
+}
+
+/// read and validate the first packet of a connection. On failure the
+/// protocol level is returned together with the refusal, so that the
+/// CONNACK can be sent in the format the client understands. Returns
+/// `None` if the connection was closed before a packet arrived
+fn read_connect(
+    stream: &mut ClientStream,
+    max_packet_size: usize,
+) -> Result<Option<ConnectPacket>, (u8, ConnectRefusal)> {
+    let raw = match framing::read_packet(stream, max_packet_size) {
+        Ok(raw) => raw,
+        Err(FrameError::Closed(_)) => return Ok(None),
+        Err(FrameError::TooLarge(_)) => return Err((4, ConnectRefusal::PacketTooLarge)),
+        Err(FrameError::Malformed(_)) => return Err((4, ConnectRefusal::MalformedPacket)),
+    };
+    let protocol_version = connect::protocol_level(&raw).unwrap_or(4);
+    let refuse = |refusal| (protocol_version, refusal);
 
-        let writer = WriterProcess::start((stream.clone(), connect_packet.clone()), None);
-        // Let the coordinator know that we joined.
-        let writer_ref = WriterRef {
-            process: Some(writer.clone()),
-            client_id: connect_packet.client_id.clone(),
-            session_id: Uuid::new_v4(),
-            is_persistent_session: !connect_packet.clean_session,
+    let protocol_version = connect::validate_header(&raw).map_err(refuse)?;
+    let packet = match raw.decode(protocol_version) {
+        Ok(MqttPacket::Connect(packet)) => packet,
+        _ => return Err(refuse(ConnectRefusal::MalformedPacket)),
+    };
+    connect::validate_client_id(&packet.client_id, protocol_version, packet.clean_session)
+        .map_err(refuse)?;
+    Ok(Some(packet))
+}
+
+/// the Response Information for a v5 client that requested it
+fn response_information(connect_packet: &ConnectPacket, root: &str) -> Option<String> {
+    let requested = connect_packet
+        .properties
+        .as_ref()
+        .and_then(|properties| properties.request_response_information)
+        .unwrap_or(false);
+    if !requested {
+        return None;
+    }
+    connect::response_information(root, &connect_packet.client_id)
+}
+
+/// run the AUTH exchange that a v5 client starts with the Authentication
+/// Method of its CONNECT. Returns the authenticated username and the data
+/// that is sent along with the CONNACK
+fn authenticate(
+    stream: &mut ClientStream,
+    config: &ConnectionConfig,
+    connect_packet: &ConnectPacket,
+    method: &str,
+) -> Result<(String, Option<Vec<u8>>), ConnectRefusal> {
+    let mut authenticator = auth::authenticator(method, config.scram_credentials_file.as_deref())
+        .ok_or(ConnectRefusal::BadAuthenticationMethod)?;
+    let mut data = connect_packet
+        .properties
+        .as_ref()
+        .and_then(|properties| properties.authentication_data.clone())
+        .unwrap_or_default();
+    loop {
+        let challenge = match authenticator.step(&data) {
+            AuthStep::Continue(challenge) => challenge,
+            AuthStep::Success { username, data } => return Ok((username, data)),
+            AuthStep::Failure(reason) => {
+                lunatic_log::info!(
+                    "[Client] Authentication of {} failed | {}",
+                    connect_packet.client_id,
+                    reason
+                );
+                return Err(ConnectRefusal::NotAuthorized);
+            }
         };
-        let _ = coordinator.connect(
-            this.clone(),
-            writer_ref.clone(),
-            connect_packet.clean_session,
+        let packet = auth::auth_packet(
+            reason_code::CONTINUE_AUTHENTICATION,
+            method,
+            Some(challenge),
         );
+        match packet.encode(5) {
+            Ok(encoded) if stream.write_all(&encoded).is_ok() => {}
+            _ => return Err(ConnectRefusal::NotAuthorized),
+        }
+        data = match framing::read_packet(stream, config.max_packet_size)
+            .and_then(|raw| raw.decode(5))
+        {
+            Ok(MqttPacket::Auth(packet))
+                if packet.reason_code == reason_code::CONTINUE_AUTHENTICATION =>
+            {
+                match auth::method_and_data(&packet) {
+                    (Some(client_method), data) if client_method == method => data,
+                    _ => return Err(ConnectRefusal::ProtocolError),
+                }
+            }
+            Ok(_) => return Err(ConnectRefusal::ProtocolError),
+            // an abandoned exchange counts as a failed attempt
+            Err(FrameError::Closed(_)) => return Err(ConnectRefusal::NotAuthorized),
+            Err(FrameError::TooLarge(_)) => return Err(ConnectRefusal::PacketTooLarge),
+            Err(FrameError::Malformed(_)) => return Err(ConnectRefusal::MalformedPacket),
+        };
+    }
+}
 
-        Process::spawn_link(
-            (
-                this.clone(),
-                stream,
-                writer_ref.clone(),
-                connect_packet.clone(),
-                coordinator.clone(),
-            ),
-            |(_, stream, writer_ref, connect_packet, coordinator), _: Mailbox<()>| {
-                let mut reader = PacketDecoder::from_stream(stream);
-                let started_at = SystemTime::now();
-                let writer = writer_ref.process.as_ref().unwrap();
-
-                loop {
-                    match reader.decode_packet(connect_packet.protocol_version) {
-                        Ok(message) => {
-                            lunatic_log::debug!("Received packet {:?}", message);
-                            match message {
-                                MqttPacket::Subscribe(sub) => {
-                                    coordinator.subscribe(sub, writer_ref.clone());
-                                }
-                                MqttPacket::Publish(packet) => {
-                                    coordinator.publish(packet, writer_ref.clone(), started_at);
-                                }
-                                MqttPacket::Pingreq => {
-                                    if writer.write_packet(MqttPacket::Pingresp) {
-                                        lunatic_log::debug!("Sent pong");
-                                    } else {
-                                        lunatic_log::error!("Failed to send pong");
-                                    }
-                                }
-                                MqttPacket::Puback(packet)
-                                | MqttPacket::Pubrel(packet)
-                                | MqttPacket::Pubrec(packet) => {
-                                    coordinator.confirm(packet, writer_ref.clone());
-                                }
-                                MqttPacket::Pubcomp(_packet) => {
-                                    lunatic_log::debug!(
-                                        "[Client {}] received pubcomp",
-                                        writer_ref.client_id
-                                    );
-                                }
-                                other => lunatic_log::debug!("Received other packet {:?}", other),
+/// read packets until the connection is closed. Returns the reason code
+/// if the client is disconnected because it violated the protocol
+fn read_packets(
+    stream: &mut ClientStream,
+    config: &ConnectionConfig,
+    keep_alive: u16,
+    writer_ref: &WriterRef,
+    auth_method: Option<&str>,
+    coordinator: &ProcessRef<CoordinatorProcess>,
+) -> Option<u8> {
+    let started_at = SystemTime::now();
+    let writer = writer_ref.process.as_ref().unwrap();
+    // a client that stays silent for one and a half times its keep alive is
+    // considered gone, a keep alive of 0 turns the mechanism off
+    if keep_alive > 0 {
+        let timeout = Duration::from_millis(keep_alive as u64 * 1500);
+        if let Err(e) = stream.set_read_timeout(Some(timeout)) {
+            lunatic_log::error!(
+                "[Client {}] Failed to set the keep alive timeout {}",
+                writer_ref.client_id,
+                e
+            );
+        }
+    }
+    let mut topic_aliases = InboundAliases::new(TOPIC_ALIAS_MAXIMUM);
+    let mut reauthentication: Option<Box<dyn Authenticator>> = None;
+
+    loop {
+        let message = match framing::read_packet(stream, config.max_packet_size)
+            .and_then(|raw| raw.decode(writer_ref.protocol_version))
+        {
+            Ok(message) => message,
+            Err(FrameError::Closed(e))
+                if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) =>
+            {
+                lunatic_log::info!(
+                    "[Client {}] No packet within the keep alive of {}s",
+                    writer_ref.client_id,
+                    keep_alive
+                );
+                return Some(reason_code::KEEP_ALIVE_TIMEOUT);
+            }
+            Err(FrameError::Closed(e)) => {
+                lunatic_log::debug!(
+                    "[Client {}] Connection closed {:?}",
+                    writer_ref.client_id,
+                    e
+                );
+                return None;
+            }
+            Err(FrameError::Malformed(e)) => {
+                lunatic_log::error!("[Client {}] Malformed packet {}", writer_ref.client_id, e);
+                return Some(reason_code::MALFORMED_PACKET);
+            }
+            Err(FrameError::TooLarge(size)) => {
+                lunatic_log::error!(
+                    "[Client {}] Packet of {} bytes exceeds the limit",
+                    writer_ref.client_id,
+                    size
+                );
+                return Some(reason_code::PACKET_TOO_LARGE);
+            }
+        };
+        lunatic_log::debug!("Received packet {:?}", message);
+        match message {
+            MqttPacket::Subscribe(sub) => {
+                let identifier = sub
+                    .properties
+                    .as_ref()
+                    .and_then(|properties| properties.subscription_identifier);
+                // 0 is not a valid subscription identifier
+                if identifier == Some(0) {
+                    lunatic_log::error!(
+                        "[Client {}] Sent subscription identifier 0",
+                        writer_ref.client_id
+                    );
+                    return Some(reason_code::PROTOCOL_ERROR);
+                }
+                coordinator.subscribe(sub, writer_ref.clone());
+            }
+            MqttPacket::Publish(mut packet) => {
+                // v3 clients have no Receive Maximum to respect
+                if let (5, Some(message_id)) = (writer_ref.protocol_version, packet.message_id) {
+                    if packet.qos > 0 && !writer.receive_publish(message_id) {
+                        lunatic_log::error!(
+                            "[Client {}] Exceeded the Receive Maximum of {}",
+                            writer_ref.client_id,
+                            RECEIVE_MAXIMUM
+                        );
+                        return Some(reason_code::RECEIVE_MAXIMUM_EXCEEDED);
+                    }
+                }
+                if let Err(reason_code) = topic_aliases.resolve(&mut packet) {
+                    lunatic_log::error!(
+                        "[Client {}] Invalid topic alias in publish to {:?}",
+                        writer_ref.client_id,
+                        packet.topic
+                    );
+                    return Some(reason_code);
+                }
+                if let Err(e) = validate_topic_name(&packet.topic) {
+                    lunatic_log::error!(
+                        "[Client {}] Invalid topic name {:?} | {:?}",
+                        writer_ref.client_id,
+                        packet.topic,
+                        e
+                    );
+                    // v5 clients learn about it from the acknowledgement, all
+                    // others have no way of handling the error and are disconnected
+                    if writer_ref.protocol_version == 5 && packet.qos > 0 {
+                        writer_ref.reject_publish(&packet, PubackPubrecCode::TopicNameInvalid);
+                        continue;
+                    }
+                    return Some(reason_code::TOPIC_NAME_INVALID);
+                }
+                // the Response Topic is forwarded to the subscribers which
+                // publish their responses to it, so it has to be a valid topic name
+                if let Some(response_topic) = packet
+                    .properties
+                    .as_ref()
+                    .and_then(|properties| properties.response_topic.as_ref())
+                {
+                    if let Err(e) = validate_topic_name(response_topic) {
+                        lunatic_log::error!(
+                            "[Client {}] Invalid response topic {:?} | {:?}",
+                            writer_ref.client_id,
+                            response_topic,
+                            e
+                        );
+                        return Some(reason_code::PROTOCOL_ERROR);
+                    }
+                }
+                if !has_valid_payload_format(&packet) {
+                    lunatic_log::error!(
+                        "[Client {}] Payload of {:?} is not valid UTF-8",
+                        writer_ref.client_id,
+                        packet.topic
+                    );
+                    if packet.qos > 0 {
+                        writer_ref.reject_publish(&packet, PubackPubrecCode::PayloadFormatInvalid);
+                        continue;
+                    }
+                    return Some(reason_code::PAYLOAD_FORMAT_INVALID);
+                }
+                coordinator.publish(packet, writer_ref.clone(), started_at);
+            }
+            MqttPacket::Pingreq => {
+                if writer.write_packet(MqttPacket::Pingresp) {
+                    lunatic_log::debug!("Sent pong");
+                } else {
+                    lunatic_log::error!("Failed to send pong");
+                }
+            }
+            MqttPacket::Puback(packet)
+            | MqttPacket::Pubrel(packet)
+            | MqttPacket::Pubrec(packet) => {
+                coordinator.confirm(packet, writer_ref.clone());
+            }
+            MqttPacket::Pubcomp(_packet) => {
+                lunatic_log::debug!("[Client {}] received pubcomp", writer_ref.client_id);
+            }
+            MqttPacket::Connect(_) => {
+                lunatic_log::error!(
+                    "[Client {}] Received a second CONNECT",
+                    writer_ref.client_id
+                );
+                return Some(reason_code::PROTOCOL_ERROR);
+            }
+            MqttPacket::Disconnect(packet) => {
+                lunatic_log::debug!("[Client {}] Received DISCONNECT", writer_ref.client_id);
+                if let Some(interval) = packet
+                    .properties
+                    .and_then(|properties| properties.session_expiry_interval)
+                {
+                    coordinator.set_session_expiry(writer_ref.clone(), interval);
+                }
+                return None;
+            }
+            MqttPacket::Auth(packet) => {
+                // only clients that authenticated with a method on CONNECT
+                // may re-authenticate, and only with the same method
+                let (method, data) = auth::method_and_data(&packet);
+                let method = match (method, auth_method) {
+                    (Some(method), Some(auth_method)) if method == auth_method => method,
+                    _ => {
+                        lunatic_log::error!(
+                            "[Client {}] Unexpected AUTH with method {:?}",
+                            writer_ref.client_id,
+                            method
+                        );
+                        return Some(reason_code::PROTOCOL_ERROR);
+                    }
+                };
+                let step = match (packet.reason_code, reauthentication.as_mut()) {
+                    (reason_code::REAUTHENTICATE, None) => {
+                        match auth::authenticator(method, config.scram_credentials_file.as_deref())
+                        {
+                            Some(authenticator) => {
+                                reauthentication.insert(authenticator).step(&data)
                             }
+                            None => return Some(reason_code::BAD_AUTHENTICATION_METHOD),
                         }
-                        Err(err) => panic!("A decoding error ocurred: {:?}", err),
-                    };
+                    }
+                    (reason_code::CONTINUE_AUTHENTICATION, Some(authenticator)) => {
+                        authenticator.step(&data)
+                    }
+                    _ => return Some(reason_code::PROTOCOL_ERROR),
+                };
+                match step {
+                    AuthStep::Continue(challenge) => {
+                        writer.write_packet(auth::auth_packet(
+                            reason_code::CONTINUE_AUTHENTICATION,
+                            method,
+                            Some(challenge),
+                        ));
+                    }
+                    // the identity of a connection cannot change, the ACL
+                    // decisions for its subscriptions were based on it
+                    AuthStep::Success { username, data }
+                        if Some(&username) == writer_ref.username.as_ref() =>
+                    {
+                        lunatic_log::info!(
+                            "[Client {}] Re-authenticated {}",
+                            writer_ref.client_id,
+                            username
+                        );
+                        reauthentication = None;
+                        writer.write_packet(auth::auth_packet(0, method, data));
+                    }
+                    AuthStep::Success { username, .. } => {
+                        lunatic_log::error!(
+                            "[Client {}] Re-authenticated as a different user {}",
+                            writer_ref.client_id,
+                            username
+                        );
+                        return Some(reason_code::NOT_AUTHORIZED_V5);
+                    }
+                    AuthStep::Failure(reason) => {
+                        lunatic_log::info!(
+                            "[Client {}] Re-authentication failed | {}",
+                            writer_ref.client_id,
+                            reason
+                        );
+                        return Some(reason_code::NOT_AUTHORIZED_V5);
+                    }
                 }
-            },
-        );
+            }
+            other => lunatic_log::debug!("Received other packet {:?}", other),
+        }
+    }
+}
 
-        let is_v5 = connect_packet.protocol_version == 5;
+/// reason codes of 0x80 and above end a QoS 2 flow with the PUBREC
+fn is_error_code(code: &PubackPubrecCode) -> bool {
+    !matches!(
+        code,
+        PubackPubrecCode::Success | PubackPubrecCode::NoMatchingSubscribers
+    )
+}
 
-        // send connack response to client
-        writer.write_packet(MqttPacket::Connack(ConnackPacket {
-            properties: None,
-            reason_code: if is_v5 { Some(0) } else { None },
-            return_code: if !is_v5 { Some(0) } else { None },
-            session_present: false,
-        }));
+/// a payload that is marked as UTF-8 by the publisher has to be valid UTF-8
+fn has_valid_payload_format(packet: &PublishPacket) -> bool {
+    match &packet.properties {
+        Some(PublishProperties {
+            payload_format_indicator: Some(true),
+            ..
+        }) => std::str::from_utf8(&packet.payload).is_ok(),
+        _ => true,
+    }
+}
 
-        ClientProcess {
-            // this,
-            // coordinator,
-            // writer: writer_ref,
-            // connect_packet,
-            // is_v5,
-            // client_id,
+/// v3 packets cannot carry properties or reason codes, whatever was set
+/// for v5 clients is dropped before encoding
+fn without_v5_fields(packet: MqttPacket) -> MqttPacket {
+    match packet {
+        MqttPacket::Connack(mut connack) => {
+            connack.properties = None;
+            MqttPacket::Connack(connack)
+        }
+        MqttPacket::Publish(mut publish) => {
+            publish.properties = None;
+            MqttPacket::Publish(publish)
+        }
+        MqttPacket::Puback(confirmation) => {
+            MqttPacket::Puback(confirmation_without_v5_fields(confirmation))
+        }
+        MqttPacket::Pubrec(confirmation) => {
+            MqttPacket::Pubrec(confirmation_without_v5_fields(confirmation))
+        }
+        MqttPacket::Pubrel(confirmation) => {
+            MqttPacket::Pubrel(confirmation_without_v5_fields(confirmation))
         }
+        MqttPacket::Pubcomp(confirmation) => {
+            MqttPacket::Pubcomp(confirmation_without_v5_fields(confirmation))
+        }
+        MqttPacket::Suback(mut suback) => {
+            suback.properties = None;
+            MqttPacket::Suback(suback)
+        }
+        MqttPacket::Unsuback(mut unsuback) => {
+            unsuback.properties = None;
+            MqttPacket::Unsuback(unsuback)
+        }
+        other => other,
+    }
+}
+
+fn confirmation_without_v5_fields(mut confirmation: ConfirmationPacket) -> ConfirmationPacket {
+    confirmation.properties = None;
+    confirmation.puback_reason_code = None;
+    confirmation.pubcomp_reason_code = None;
+    confirmation
+}
+
+/// a v5 DISCONNECT. The reason string is left out if the packet would
+/// exceed the maximum packet size of the client otherwise (§3.14.2.2.3)
+fn disconnect_packet(
+    reason_code: u8,
+    reason_string: Option<String>,
+    maximum_packet_size: Option<u32>,
+) -> MqttPacket {
+    let packet = MqttPacket::Disconnect(DisconnectPacket {
+        reason_code: Some(reason_code),
+        properties: reason_string.map(|reason_string| DisconnectProperties {
+            session_expiry_interval: None,
+            reason_string: Some(reason_string),
+            user_properties: None,
+            server_reference: None,
+        }),
+    });
+    let too_large = match (maximum_packet_size, packet.encode(5)) {
+        (Some(maximum), Ok(encoded)) => encoded.len() > maximum as usize,
+        _ => false,
+    };
+    if !too_large {
+        return packet;
     }
+    MqttPacket::Disconnect(DisconnectPacket {
+        reason_code: Some(reason_code),
+        properties: None,
+    })
 }
 
 // =====================================
 // Writer process
 // =====================================
 pub struct WriterProcess {
-    stream: TcpStream,
+    stream: ClientStream,
     connect_packet: ConnectPacket,
     client_id: String,
-    // is_v5: bool,
+    is_v5: bool,
+    topic_aliases: OutboundAliases,
+    /// message ids of QoS 1 and 2 publishes of the client that are not yet acknowledged
+    inbound: HashSet<u16>,
+    /// set once the broker closed the connection, nothing is written afterwards
+    disconnected: bool,
 }
 
 #[abstract_process(visibility = pub)]
 impl WriterProcess {
     #[init]
-    fn init(_: ProcessRef<Self>, (stream, connect_packet): (TcpStream, ConnectPacket)) -> Self {
+    fn init(_: ProcessRef<Self>, (stream, connect_packet): (ClientStream, ConnectPacket)) -> Self {
         let client_id = connect_packet.client_id.clone();
-        // TODO: is_v5 will be needed for implementing v5 protocol
-        // let is_v5 = connect_packet.protocol_version == 5;
+        let is_v5 = connect_packet.protocol_version == 5;
+        let topic_alias_maximum = connect_packet
+            .properties
+            .as_ref()
+            .and_then(|properties| properties.topic_alias_maximum)
+            .unwrap_or(0);
         WriterProcess {
+            topic_aliases: OutboundAliases::new(topic_alias_maximum),
+            inbound: HashSet::new(),
+            disconnected: false,
             stream,
             connect_packet,
-            // is_v5,
+            is_v5,
             client_id,
         }
     }
@@ -159,13 +746,86 @@ impl WriterProcess {
         lunatic_log::debug!("Link trapped");
     }
 
+    /// count a QoS 1 or 2 publish of the client until it is acknowledged,
+    /// returns false if the client exceeds the Receive Maximum of the broker
+    #[handle_request]
+    fn receive_publish(&mut self, message_id: u16) -> bool {
+        self.inbound.insert(message_id);
+        self.inbound.len() <= RECEIVE_MAXIMUM as usize
+    }
+
+    /// close the connection from the broker side. v5 clients learn the reason
+    /// from a DISCONNECT, v3 has no DISCONNECT from the server so their socket
+    /// is just closed once the reader and writer stop
+    #[handle_request]
+    fn disconnect(&mut self, reason_code: u8, reason_string: Option<String>) {
+        if self.disconnected {
+            return;
+        }
+        lunatic_log::info!(
+            "[Writer {}] Disconnecting with reason {:#04x}",
+            self.client_id,
+            reason_code
+        );
+        if self.is_v5 {
+            let maximum_packet_size = self
+                .connect_packet
+                .properties
+                .as_ref()
+                .and_then(|properties| properties.maximum_packet_size);
+            self.write_packet(disconnect_packet(
+                reason_code,
+                reason_string,
+                maximum_packet_size,
+            ));
+        }
+        self.disconnected = true;
+    }
+
+    /// answer a websocket control frame that the reader received
+    #[handle_message]
+    fn write_control_frame(&mut self, opcode: u8, payload: Vec<u8>) {
+        if self.disconnected && opcode != OPCODE_CLOSE {
+            return;
+        }
+        if let ClientStream::WebSocket(ws) = &mut self.stream {
+            if let Err(e) = ws.write_control_frame(opcode, &payload) {
+                lunatic_log::error!("Failed to write to stream {}", e);
+            }
+        }
+    }
+
     #[handle_request]
     fn write_packet(&mut self, packet: MqttPacket) -> bool {
+        if self.disconnected {
+            return false;
+        }
         lunatic_log::debug!(
             "[Writer {}] Received Mqtt Packet {:?}",
             self.client_id,
             packet
         );
+        // an acknowledgement to the client ends the flow of one of its publishes
+        match &packet {
+            MqttPacket::Puback(ack) | MqttPacket::Pubcomp(ack) => {
+                self.inbound.remove(&ack.message_id);
+            }
+            MqttPacket::Pubrec(ack)
+                if ack.puback_reason_code.as_ref().is_some_and(is_error_code) =>
+            {
+                self.inbound.remove(&ack.message_id);
+            }
+            _ => {}
+        }
+        let packet = match packet {
+            _ if !self.is_v5 => without_v5_fields(packet),
+            // topic aliases only apply to the connection they were set on
+            MqttPacket::Publish(mut publish) => {
+                self.topic_aliases.apply(&mut publish);
+                MqttPacket::Publish(publish)
+            }
+            other => other,
+        };
         match packet.encode(self.connect_packet.protocol_version) {
             Err(encode_err) => {
                 lunatic_log::error!("Failed to encode packet {}", encode_err);
@@ -0,0 +1,206 @@
+use crate::framing::MAX_PACKET_SIZE;
+use serde::{Deserialize, Serialize};
+use std::fs;
+
+/// Environment variable that can point to a different configuration file
+const CONFIG_ENV: &str = "MQTT_BROKER_CONFIG";
+const DEFAULT_CONFIG_FILE: &str = "broker.ron";
+
+/// Broker wide settings. They are read from a RON file on startup, every
+/// field that is missing from the file falls back to its default value
+/// so that an empty (or absent) file gives the same behaviour as before.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BrokerConfig {
+    /// port of the plain MQTT listener
+    pub port: u16,
+    /// path to the file with the topic access control list, if none is set
+    /// every client is allowed to publish and subscribe to every topic
+    pub acl_file: Option<String>,
+    /// settings of the MQTTS listener, it is only started if this is set
+    pub tls: Option<TlsConfig>,
+    /// settings of the MQTT over WebSockets listener, it is only started if this is set
+    pub websocket: Option<WebSocketConfig>,
+    /// port of the http server with the metrics and admin endpoints
+    pub http_port: u16,
+    /// thresholds for banning misbehaving clients
+    pub ban: BanConfig,
+    /// expiry interval in seconds for messages of v3 clients, which cannot
+    /// set one themselves. Without it their messages never expire
+    pub message_expiry_secs: Option<u32>,
+    /// expiry interval in seconds for sessions of v3 clients with
+    /// `clean_session = false`. Without it their sessions never expire
+    pub session_expiry_secs: Option<u32>,
+    /// how the member of a shared subscription group that receives a message is chosen
+    pub shared_subscription_strategy: SharedStrategy,
+    /// largest packet in bytes a client may send. Packets are read into memory
+    /// completely, so it has to stay well below the 5 MB limit of a client process
+    pub max_packet_size: usize,
+    /// RON file with the SCRAM-SHA-256 keys of every user. Without it clients
+    /// that ask for the SCRAM-SHA-256 authentication method are refused
+    pub scram_credentials_file: Option<String>,
+    /// v5 clients that request Response Information get `<root>/<client id>`
+    /// as prefix for their response topics. Only they may subscribe below it
+    pub response_topic_root: String,
+    /// seconds a graceful shutdown may take before the broker exits anyway
+    pub shutdown_timeout_secs: u64,
+    /// bearer token of the admin endpoints. Without it they refuse every request
+    pub admin_token: Option<String>,
+}
+
+impl Default for BrokerConfig {
+    fn default() -> Self {
+        BrokerConfig {
+            port: 1883,
+            acl_file: None,
+            tls: None,
+            websocket: None,
+            http_port: 3000,
+            ban: BanConfig::default(),
+            message_expiry_secs: None,
+            session_expiry_secs: None,
+            shared_subscription_strategy: SharedStrategy::default(),
+            max_packet_size: MAX_PACKET_SIZE,
+            scram_credentials_file: None,
+            response_topic_root: "responses".to_owned(),
+            shutdown_timeout_secs: 30,
+            admin_token: None,
+        }
+    }
+}
+
+/// The part of the config that every client connection needs. It is taken
+/// from the config loaded on startup and handed to the listeners
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectionConfig {
+    /// larger packets of the client are refused before they are read
+    pub max_packet_size: usize,
+    pub scram_credentials_file: Option<String>,
+    pub response_topic_root: String,
+}
+
+impl BrokerConfig {
+    pub fn connection(&self) -> ConnectionConfig {
+        ConnectionConfig {
+            max_packet_size: self.max_packet_size,
+            scram_credentials_file: self.scram_credentials_file.clone(),
+            response_topic_root: self.response_topic_root.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TlsConfig {
+    pub port: u16,
+    /// path to the PEM encoded certificate chain of the broker
+    pub cert_chain: String,
+    /// path to the PEM encoded private key of the broker
+    pub private_key: String,
+    /// path to the PEM encoded CA used to verify client certificates. Not
+    /// supported yet, the broker refuses to start if it is set
+    pub client_ca: Option<String>,
+    /// use the identity of the client certificate as username. Not
+    /// supported yet, the broker refuses to start if it is set
+    pub use_identity_as_username: bool,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        TlsConfig {
+            port: 8883,
+            cert_chain: "certs/broker.pem".to_owned(),
+            private_key: "certs/broker.key".to_owned(),
+            client_ca: None,
+            use_identity_as_username: false,
+        }
+    }
+}
+
+impl BrokerConfig {
+    /// load the configuration from the file in `MQTT_BROKER_CONFIG` or `broker.ron`.
+    /// It is loaded once on startup and passed to the processes that need it,
+    /// so that a broken file cannot take down a running broker
+    pub fn load() -> Result<BrokerConfig, String> {
+        let path = std::env::var(CONFIG_ENV).unwrap_or_else(|_| DEFAULT_CONFIG_FILE.to_owned());
+        match fs::read_to_string(&path) {
+            Ok(contents) => ron::from_str::<BrokerConfig>(&contents)
+                .map_err(|e| format!("failed to parse {}: {}", path, e))
+                .and_then(BrokerConfig::validate),
+            Err(_) => {
+                lunatic_log::debug!("[config] no config file at {}, using defaults", path);
+                Ok(BrokerConfig::default())
+            }
+        }
+    }
+
+    /// refuse settings that the broker cannot honour instead of ignoring them
+    fn validate(self) -> Result<BrokerConfig, String> {
+        // the TLS streams of the lunatic runtime do not expose the peer certificate
+        if let Some(tls) = &self.tls {
+            if tls.client_ca.is_some() || tls.use_identity_as_username {
+                return Err("client certificates are not supported yet, remove \
+                    `client_ca` and `use_identity_as_username` from `tls`"
+                    .to_owned());
+            }
+        }
+        Ok(self)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebSocketConfig {
+    pub port: u16,
+    /// http path that clients have to request for the upgrade
+    pub path: String,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        WebSocketConfig {
+            port: 8080,
+            path: "/mqtt".to_owned(),
+        }
+    }
+}
+
+/// Peers are banned once they cross one of the thresholds within `window_secs`.
+/// Every further ban of the same peer lasts twice as long, up to `max_ban_secs`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BanConfig {
+    pub enabled: bool,
+    pub window_secs: u64,
+    pub max_connects: u32,
+    pub max_malformed_packets: u32,
+    pub max_failed_auth: u32,
+    pub base_ban_secs: u64,
+    pub max_ban_secs: u64,
+    /// bans are stored in this file so that they survive restarts
+    pub file: String,
+}
+
+impl Default for BanConfig {
+    fn default() -> Self {
+        BanConfig {
+            enabled: true,
+            window_secs: 60,
+            max_connects: 30,
+            max_malformed_packets: 3,
+            max_failed_auth: 5,
+            base_ban_secs: 60,
+            max_ban_secs: 24 * 60 * 60,
+            file: "persistence/bans.ron".to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SharedStrategy {
+    #[default]
+    RoundRobin,
+    Random,
+    /// the member with the fewest unacknowledged messages
+    LeastInflight,
+}
@@ -0,0 +1,306 @@
+//! Validation of the CONNECT packet that opens every connection
+use crate::acl::is_literal_level;
+use crate::framing::RawPacket;
+use crate::reason_code;
+use crate::topic_alias::TOPIC_ALIAS_MAXIMUM;
+use mqtt_packet_3_5::{ConnackPacket, ConnackProperties, ConnectPacket, MqttPacket};
+
+const CONNECT: u8 = 1;
+
+/// Why a connection attempt is refused
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConnectRefusal {
+    UnsupportedProtocolVersion,
+    IdentifierRejected,
+    MalformedPacket,
+    ProtocolError,
+    PacketTooLarge,
+    Banned,
+    BadAuthenticationMethod,
+    NotAuthorized,
+}
+
+impl ConnectRefusal {
+    /// CONNACK return code for v3 clients. v3 has no codes for malformed
+    /// packets, the connection is closed without a CONNACK instead
+    pub fn return_code(self) -> Option<u8> {
+        match self {
+            ConnectRefusal::UnsupportedProtocolVersion => {
+                Some(reason_code::UNACCEPTABLE_PROTOCOL_VERSION)
+            }
+            ConnectRefusal::IdentifierRejected => Some(reason_code::IDENTIFIER_REJECTED),
+            ConnectRefusal::Banned | ConnectRefusal::NotAuthorized => {
+                Some(reason_code::NOT_AUTHORIZED)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn reason_code(self) -> u8 {
+        match self {
+            ConnectRefusal::UnsupportedProtocolVersion => reason_code::UNSUPPORTED_PROTOCOL_VERSION,
+            ConnectRefusal::IdentifierRejected => reason_code::CLIENT_IDENTIFIER_NOT_VALID,
+            ConnectRefusal::MalformedPacket => reason_code::MALFORMED_PACKET,
+            ConnectRefusal::ProtocolError => reason_code::PROTOCOL_ERROR,
+            ConnectRefusal::PacketTooLarge => reason_code::PACKET_TOO_LARGE,
+            ConnectRefusal::Banned => reason_code::BANNED,
+            ConnectRefusal::BadAuthenticationMethod => reason_code::BAD_AUTHENTICATION_METHOD,
+            ConnectRefusal::NotAuthorized => reason_code::NOT_AUTHORIZED_V5,
+        }
+    }
+
+    /// the encoded CONNACK that is sent before the connection is closed.
+    /// Clients that did not ask for v5 get the v3 format, which is the
+    /// same for MQTT 3.1 and 3.1.1
+    pub fn connack(self, protocol_version: u8) -> Option<Vec<u8>> {
+        let (packet, version) = if protocol_version == 5 {
+            (
+                ConnackPacket {
+                    properties: None,
+                    reason_code: Some(self.reason_code()),
+                    return_code: None,
+                    session_present: false,
+                },
+                5,
+            )
+        } else {
+            (
+                ConnackPacket {
+                    properties: None,
+                    reason_code: None,
+                    return_code: Some(self.return_code()?),
+                    session_present: false,
+                },
+                if protocol_version == 3 { 3 } else { 4 },
+            )
+        };
+        MqttPacket::Connack(packet).encode(version).ok()
+    }
+}
+
+/// number of unacknowledged QoS 1 and 2 publishes a v5 client may send
+pub const RECEIVE_MAXIMUM: u16 = 100;
+
+/// properties of a successful v5 CONNACK. They tell the client which
+/// features and limits of the broker it has to respect
+pub fn connack_properties(
+    connect_packet: &ConnectPacket,
+    assigned_client_id: Option<String>,
+    max_packet_size: usize,
+) -> ConnackProperties {
+    let properties = connect_packet.properties.clone().unwrap_or_default();
+    ConnackProperties {
+        assigned_client_identifier: assigned_client_id,
+        session_expiry_interval: properties.session_expiry_interval,
+        receive_maximum: Some(RECEIVE_MAXIMUM),
+        maximum_packet_size: Some(max_packet_size as u32),
+        topic_alias_maximum: Some(TOPIC_ALIAS_MAXIMUM),
+        retain_available: Some(true),
+        wildcard_subscription_available: Some(true),
+        subscription_identifiers_available: Some(true),
+        shared_subscription_available: Some(true),
+        ..Default::default()
+    }
+}
+
+/// prefix of the response topics of a client, returned as Response
+/// Information. Client ids that contain `/` or wildcards would escape the
+/// scope of their client, they get none
+pub fn response_information(root: &str, client_id: &str) -> Option<String> {
+    if !is_literal_level(client_id) {
+        return None;
+    }
+    Some(format!("{}/{}", root, client_id))
+}
+
+/// the protocol level of a CONNECT, it is needed to answer in the right
+/// format even if the rest of the packet is invalid
+pub fn protocol_level(raw: &RawPacket) -> Option<u8> {
+    let body = raw.body();
+    let name_len = u16::from_be_bytes([*body.first()?, *body.get(1)?]) as usize;
+    body.get(2 + name_len).copied()
+}
+
+/// check the variable header of a CONNECT before it is decoded and
+/// return the protocol level that the client requested
+pub fn validate_header(raw: &RawPacket) -> Result<u8, ConnectRefusal> {
+    // the first packet sent by a client has to be a CONNECT
+    if raw.packet_type() != CONNECT {
+        return Err(ConnectRefusal::ProtocolError);
+    }
+    let body = raw.body();
+    if body.len() < 2 {
+        return Err(ConnectRefusal::MalformedPacket);
+    }
+    let name_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+    let (name, level, flags) = match body.get(2..name_len + 4) {
+        Some(header) => (&header[..name_len], header[name_len], header[name_len + 1]),
+        None => return Err(ConnectRefusal::MalformedPacket),
+    };
+    // MQTT 3.1 uses its own protocol name
+    match (name, level) {
+        (b"MQIsdp", 3) | (b"MQTT", 4 | 5) => {}
+        _ => return Err(ConnectRefusal::UnsupportedProtocolVersion),
+    }
+
+    // bit 0 of the connect flags is reserved
+    if flags & 0x01 != 0 {
+        return Err(ConnectRefusal::MalformedPacket);
+    }
+    let will = flags & 0x04 != 0;
+    let will_qos = (flags >> 3) & 0x03;
+    let will_retain = flags & 0x20 != 0;
+    if will_qos == 3 || (!will && (will_qos != 0 || will_retain)) {
+        return Err(ConnectRefusal::MalformedPacket);
+    }
+    // v5 allows a password without a username, v3 does not
+    let password = flags & 0x40 != 0;
+    let username = flags & 0x80 != 0;
+    if level != 5 && password && !username {
+        return Err(ConnectRefusal::MalformedPacket);
+    }
+    Ok(level)
+}
+
+/// An empty client id is allowed and replaced by the broker, except for
+/// v3 clients that want a persistent session. MQTT 3.1 clients always need
+/// an id of at most 23 characters. Control characters are rejected because
+/// the id shows up in logs and persisted files.
+pub fn validate_client_id(
+    client_id: &str,
+    protocol_version: u8,
+    clean_session: bool,
+) -> Result<(), ConnectRefusal> {
+    if client_id.is_empty() && protocol_version != 5 && !clean_session {
+        return Err(ConnectRefusal::IdentifierRejected);
+    }
+    if protocol_version == 3 && (client_id.is_empty() || client_id.chars().count() > 23) {
+        return Err(ConnectRefusal::IdentifierRejected);
+    }
+    if client_id.chars().any(char::is_control) {
+        return Err(ConnectRefusal::IdentifierRejected);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framing::read_packet;
+    use std::io::Cursor;
+
+    fn connect(name: &[u8], level: u8, flags: u8) -> RawPacket {
+        let mut body = vec![0, name.len() as u8];
+        body.extend_from_slice(name);
+        body.extend_from_slice(&[level, flags, 0, 60, 0, 0]);
+        let mut bytes = vec![0x10, body.len() as u8];
+        bytes.extend(body);
+        read_packet(&mut Cursor::new(bytes), usize::MAX).unwrap()
+    }
+
+    #[test]
+    fn protocol_name_and_level() {
+        assert_eq!(validate_header(&connect(b"MQTT", 4, 0x02)), Ok(4));
+        assert_eq!(validate_header(&connect(b"MQTT", 5, 0x02)), Ok(5));
+        assert_eq!(validate_header(&connect(b"MQIsdp", 3, 0x02)), Ok(3));
+        assert_eq!(
+            validate_header(&connect(b"MQTT", 6, 0x02)),
+            Err(ConnectRefusal::UnsupportedProtocolVersion)
+        );
+        assert_eq!(
+            validate_header(&connect(b"MQTT", 3, 0x02)),
+            Err(ConnectRefusal::UnsupportedProtocolVersion)
+        );
+        assert_eq!(
+            validate_header(&connect(b"MQIsdp", 4, 0x02)),
+            Err(ConnectRefusal::UnsupportedProtocolVersion)
+        );
+        assert_eq!(
+            validate_header(&connect(b"HTTP", 4, 0x02)),
+            Err(ConnectRefusal::UnsupportedProtocolVersion)
+        );
+        assert_eq!(protocol_level(&connect(b"MQTT", 5, 0x02)), Some(5));
+        assert_eq!(protocol_level(&connect(b"MQIsdp", 3, 0x02)), Some(3));
+    }
+
+    #[test]
+    fn connect_flags() {
+        // reserved bit
+        assert_eq!(
+            validate_header(&connect(b"MQTT", 4, 0x03)),
+            Err(ConnectRefusal::MalformedPacket)
+        );
+        // will qos without will flag
+        assert_eq!(
+            validate_header(&connect(b"MQTT", 4, 0x08)),
+            Err(ConnectRefusal::MalformedPacket)
+        );
+        // will qos 3
+        assert_eq!(
+            validate_header(&connect(b"MQTT", 4, 0x1C)),
+            Err(ConnectRefusal::MalformedPacket)
+        );
+        // password without username is only allowed in v5
+        assert_eq!(
+            validate_header(&connect(b"MQTT", 4, 0x40)),
+            Err(ConnectRefusal::MalformedPacket)
+        );
+        assert_eq!(
+            validate_header(&connect(b"MQIsdp", 3, 0x40)),
+            Err(ConnectRefusal::MalformedPacket)
+        );
+        assert_eq!(validate_header(&connect(b"MQTT", 5, 0x40)), Ok(5));
+    }
+
+    #[test]
+    fn first_packet_has_to_be_connect() {
+        let ping = read_packet(&mut Cursor::new(vec![0xC0, 0x00]), usize::MAX).unwrap();
+        assert_eq!(validate_header(&ping), Err(ConnectRefusal::ProtocolError));
+    }
+
+    #[test]
+    fn client_ids() {
+        assert_eq!(validate_client_id("sensor-1", 4, false), Ok(()));
+        assert_eq!(validate_client_id("", 4, true), Ok(()));
+        assert_eq!(validate_client_id("", 5, false), Ok(()));
+        assert_eq!(
+            validate_client_id("", 4, false),
+            Err(ConnectRefusal::IdentifierRejected)
+        );
+        // a rejected id is answered with a CONNACK instead of dropping the connection
+        assert_eq!(ConnectRefusal::IdentifierRejected.return_code(), Some(0x02));
+        assert_eq!(ConnectRefusal::IdentifierRejected.reason_code(), 0x85);
+        assert!(ConnectRefusal::IdentifierRejected.connack(4).is_some());
+        assert_eq!(
+            validate_client_id("sensor\u{0}", 5, true),
+            Err(ConnectRefusal::IdentifierRejected)
+        );
+        // MQTT 3.1 limits the id to 23 characters and does not allow an empty one
+        assert_eq!(
+            validate_client_id("gateway-0123456789abcde", 3, true),
+            Ok(())
+        );
+        assert_eq!(
+            validate_client_id("gateway-0123456789abcdef", 3, true),
+            Err(ConnectRefusal::IdentifierRejected)
+        );
+        assert_eq!(
+            validate_client_id("", 3, true),
+            Err(ConnectRefusal::IdentifierRejected)
+        );
+        assert_eq!(
+            validate_client_id("gateway-0123456789abcdef", 4, true),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn response_topic_prefix() {
+        assert_eq!(
+            response_information("responses", "sensor-1"),
+            Some("responses/sensor-1".to_owned())
+        );
+        assert_eq!(response_information("responses", "site/sensor"), None);
+        assert_eq!(response_information("responses", "sensor+"), None);
+    }
+}
@@ -1,33 +1,43 @@
 use std::collections::HashMap;
 
-use crate::client::{ClientProcess, WriterProcessHandler};
+use crate::acl::AclEngine;
+use crate::client::{ClientProcess, WriterProcess, WriterProcessHandler};
+use crate::config::BrokerConfig;
+use crate::inspect::{ClientInfo, MessageInfo, TopicInfo};
 use crate::message_store::MessageStore;
 use crate::metrics::{MetricsProcess, MetricsProcessHandler};
-use crate::persistence::{self, FileLog};
+use crate::persistence::{self, FileLog, WalStats};
+use crate::reason_code;
+use crate::retained::{RetainedMessage, RetainedStore};
 use crate::structure::{
-    Client, CompletionMessage, ConfirmationMessage, PublishContext, PublishJob, PublishMessage,
-    QueueMessage, Receiver, ReleaseMessage, WriterRef,
+    Client, CompletionMessage, ConfirmationMessage, CopyTarget, OfflineMessage, OfflineSession,
+    PublishContext, PublishJob, PublishMessage, QueueMessage, Receiver, ReleaseMessage,
+    SubscriptionOptions, WriterRef, SESSION_NEVER_EXPIRES,
 };
-use crate::topic_tree::TopicTree;
+use crate::topic_tree::{split_shared_filter, validate_topic_filter, TopicTree};
 use lunatic::abstract_process;
 use lunatic::{host, process::ProcessRef, supervisor::Supervisor};
 use mqtt_packet_3_5::{
-    ConfirmationPacket, Granted, MqttPacket, PacketType, PublishPacket, SubackPacket,
-    SubscribePacket,
+    ConfirmationPacket, Granted, MqttPacket, PacketType, PubackPubrecCode, PubcompPubrelCode,
+    PublishPacket, PublishProperties, SubackPacket, SubscribePacket,
 };
 use serde::{Deserialize, Serialize};
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 use uuid::Uuid;
 
 /// The `CoordinatorSup` is supervising one global instance of the `CoordinatorProcess`.
 pub struct CoordinatorSup;
 impl Supervisor for CoordinatorSup {
-    type Arg = String;
+    type Arg = (String, BrokerConfig);
     type Children = CoordinatorProcess;
 
-    fn init(config: &mut lunatic::supervisor::SupervisorConfig<Self>, name: Self::Arg) {
+    fn init(
+        config: &mut lunatic::supervisor::SupervisorConfig<Self>,
+        (name, broker_config): Self::Arg,
+    ) {
         // Always register the `CoordinatorProcess` under the name passed to the supervisor.
-        config.children_args(((), Some(name)))
+        // A restarted coordinator gets the config that was loaded on startup
+        config.children_args((broker_config, Some(name)))
     }
 }
 
@@ -37,6 +47,19 @@ pub struct CoordinatorProcess {
     metrics: ProcessRef<MetricsProcess>,
     topic_tree: TopicTree,
     wal: FileLog,
+    acl: AclEngine,
+    default_message_expiry: Option<u32>,
+    default_session_expiry: Option<u32>,
+    offline_sessions: HashMap<String, OfflineSession>,
+    retained: RetainedStore,
+    /// no new jobs are handed out to the worker while the broker shuts down
+    draining: bool,
+    /// set once the worker asked for a job while draining, which means
+    /// that it finished the job it was working on
+    drained: bool,
+    /// writers of ended connections, the job of the worker may still refer
+    /// to them. They are stopped once the worker asks for the next job
+    retired_writers: Vec<ProcessRef<WriterProcess>>,
 }
 
 impl CoordinatorProcess {
@@ -126,12 +149,186 @@ impl CoordinatorProcess {
         let queue = self.topic_tree.get_by_id(queue_id);
         queue.drop_inactive_subs(inactive_subs);
     }
+
+    /// remove the subscriptions of a session and the messages that wait for it
+    fn purge_session(&mut self, writer: &WriterRef) {
+        let now = SystemTime::now();
+        self.topic_tree.remove_subscriber(writer);
+        let dropped = self.messages.drop_session_messages(writer.session_id);
+        for (message_uuid, ctx) in dropped.iter().filter(|(_, ctx)| ctx.packet.qos > 0) {
+            self.wal.append_completion(*message_uuid, now);
+            // the publisher is still waiting for the acknowledgement
+            ctx.sender
+                .reject_publish(&ctx.packet, PubackPubrecCode::NoMatchingSubscribers);
+        }
+        self.wal.append_session_purge(writer, now);
+        lunatic_log::debug!(
+            "[Coordinator] Purged session {} of client {} with {} waiting messages",
+            writer.session_id,
+            writer.client_id,
+            dropped.len()
+        );
+    }
+
+    /// purge all offline sessions whose expiry interval elapsed
+    fn reap_sessions(&mut self, now: SystemTime) {
+        let expired: Vec<String> = self
+            .offline_sessions
+            .iter()
+            .filter(|(_, session)| session.is_expired(now))
+            .map(|(client_id, _)| client_id.clone())
+            .collect();
+        for client_id in expired {
+            if let Some(session) = self.offline_sessions.remove(&client_id) {
+                self.purge_session(&session.writer);
+            }
+        }
+    }
+
+    /// keep the session of a client whose connection ended until it expires.
+    /// Its subscriptions stay in the topic tree without a writer
+    fn end_session(&mut self, client: Client) {
+        let offline = client.writer.offline();
+        self.messages.take_offline(&offline);
+        let expires_at = match client.session_expiry_interval {
+            0 => return self.purge_session(&client.writer),
+            SESSION_NEVER_EXPIRES => None,
+            secs => Some(SystemTime::now() + Duration::from_secs(secs as u64)),
+        };
+        self.topic_tree
+            .replace_subscriber(&client.writer, offline.clone());
+        self.offline_sessions.insert(
+            offline.client_id.clone(),
+            OfflineSession {
+                writer: offline,
+                expires_at,
+                queued: vec![],
+            },
+        );
+    }
+
+    /// keep a QoS 1 or 2 message for the offline sessions it was published
+    /// to, QoS 0 messages are not queued
+    fn queue_offline(&mut self, sessions: Vec<WriterRef>, ctx: &PublishContext) {
+        if ctx.packet.qos == 0 {
+            return;
+        }
+        for writer in sessions {
+            match self.offline_sessions.get_mut(&writer.client_id) {
+                Some(session) if session.writer.session_id == writer.session_id => {
+                    session.queued.push(OfflineMessage {
+                        packet: ctx.packet.clone(),
+                        expires_at: ctx.expires_at,
+                        retained: ctx.copy_for.as_ref().is_some_and(|target| target.retained),
+                    })
+                }
+                _ => lunatic_log::error!(
+                    "[Coordinator] Session {} of client {} is not kept offline",
+                    writer.session_id,
+                    writer.client_id
+                ),
+            }
+        }
+    }
+
+    /// queue a retained message for a new subscription. It is delivered at
+    /// the lower of its QoS and the granted QoS
+    fn queue_retained(
+        &mut self,
+        mut message: RetainedMessage,
+        granted_qos: u8,
+        subscriber: &WriterRef,
+    ) {
+        message.packet.qos = message.packet.qos.min(granted_qos);
+        self.queue_copy(message.packet, message.expires_at, subscriber, true);
+    }
+
+    /// queue a copy of a message for one session through the message store,
+    /// so that QoS 1 and 2 deliveries are acknowledged like any other message
+    fn queue_copy(
+        &mut self,
+        mut packet: PublishPacket,
+        expires_at: Option<SystemTime>,
+        subscriber: &WriterRef,
+        retained: bool,
+    ) {
+        packet.dup = false;
+        packet.message_id = None;
+        if packet.qos > 0 {
+            match self.messages.free_message_id() {
+                Some(message_id) => packet.message_id = Some(message_id),
+                // every message id is in use, the message is sent at most once
+                None => packet.qos = 0,
+            }
+        }
+        let message_uuid = self.messages.register_message_id(packet.message_id);
+        let queue_id = self.topic_tree.get_by_name(packet.topic.clone()).id;
+        let target = CopyTarget {
+            sessions: vec![subscriber.session_id],
+            groups: vec![],
+            retained,
+        };
+        self.messages.insert_copy(
+            message_uuid,
+            packet,
+            queue_id,
+            SystemTime::now(),
+            expires_at,
+            target,
+        );
+    }
+
+    /// the reference to the session as long as it is connected, otherwise one without writer
+    fn session_writer(&self, writer: WriterRef) -> WriterRef {
+        match self.clients.get(&writer.client_id) {
+            Some(client) if client.writer.session_id == writer.session_id => writer,
+            _ => writer.offline(),
+        }
+    }
+
+    /// close the connection of a client by sending a DISCONNECT with the
+    /// given reason (v5 only) and stopping its reader. The writer is retired
+    pub fn terminate_client(&mut self, client: &Client, reason_code: u8) {
+        lunatic_log::info!(
+            "[Coordinator] Terminating session {} of client {} with reason {:#04x}",
+            client.writer.session_id,
+            client.writer.client_id,
+            reason_code
+        );
+        if let Some(writer) = &client.writer.process {
+            writer.disconnect(
+                reason_code,
+                reason_code::description(reason_code).map(str::to_owned),
+            );
+            self.retired_writers.push(writer.clone());
+        }
+        // killing the client process also stops the linked reader which
+        // releases the last handle to the stream and closes the socket
+        client.client.kill();
+        self.metrics.track_disconnect();
+    }
+}
+
+/// add the outcome of one subscription to the SUBACK. v5 clients get the
+/// reason code of a failure, v3 clients only learn that it failed
+fn grant(suback: &mut SubackPacket, protocol_version: u8, outcome: Result<Granted, u8>) {
+    let (granted, reason_code) = match outcome {
+        Ok(Granted::QoS0) => (Granted::QoS0, 0x00),
+        Ok(Granted::QoS1) => (Granted::QoS1, 0x01),
+        Ok(Granted::QoS2) => (Granted::QoS2, 0x02),
+        Ok(Granted::Failure) => (Granted::Failure, reason_code::UNSPECIFIED_ERROR),
+        Err(reason_code) => (Granted::Failure, reason_code),
+    };
+    suback.granted.push(granted);
+    if protocol_version == 5 {
+        suback.granted_reason_codes.push(reason_code);
+    }
 }
 
 #[abstract_process(visibility = pub)]
 impl CoordinatorProcess {
     #[init]
-    fn init(_: ProcessRef<Self>, _: ()) -> CoordinatorProcess {
+    fn init(_: ProcessRef<Self>, config: BrokerConfig) -> CoordinatorProcess {
         // Coordinator shouldn't die when a client dies. This makes the link one-directional.
         unsafe { host::api::process::die_when_link_dies(0) };
 
@@ -190,49 +387,327 @@ impl CoordinatorProcess {
                     // delete message from messages
                     MessageStore::delete_messages_by_uuid(&mut message_queue, complete.uuid);
                 }
+                // the messages of a purged session are completed by their own entries
+                persistence::Entry::SessionPurged(_) => {}
             }
         }
 
+        topic_tree.strategy = config.shared_subscription_strategy;
+
         CoordinatorProcess {
             topic_tree,
             wal: FileLog::new("persistence", "backup.log"),
+            acl: AclEngine::new(config.acl_file).response_root(config.response_topic_root),
+            default_message_expiry: config.message_expiry_secs,
+            default_session_expiry: config.session_expiry_secs,
+            offline_sessions: HashMap::new(),
+            retained: RetainedStore::default(),
+            draining: false,
+            drained: false,
+            retired_writers: vec![],
             messages: MessageStore::new(messages, message_queue, message_ids),
             clients: HashMap::new(),
             metrics: ProcessRef::<MetricsProcess>::lookup("metrics").unwrap(),
         }
     }
 
+    /// register a client and return whether a previous session was resumed.
+    /// v5 clients pass their session expiry interval, for v3 clients it is
+    /// derived from `clean_session` and the broker default
     #[handle_request]
     fn connect(
         &mut self,
         client: ProcessRef<ClientProcess>,
         writer: WriterRef,
-        should_persist: bool,
+        clean_start: bool,
+        session_expiry_interval: Option<u32>,
     ) -> bool {
-        // TODO: handle new connections and reconnections
-        // Sometimes a client that has sent a high qos message will
-        // disconnect and therefore a reference to the process
-        // should be stored to the publish message
+        let session_expiry_interval = match session_expiry_interval {
+            Some(interval) => interval,
+            None if writer.is_persistent_session => {
+                self.default_session_expiry.unwrap_or(SESSION_NEVER_EXPIRES)
+            }
+            None => 0,
+        };
+        // only one connection per client id is allowed, an existing one is taken over
+        let previous = match self.clients.remove(&writer.client_id) {
+            Some(previous) => {
+                self.terminate_client(&previous, reason_code::SESSION_TAKEN_OVER);
+                // nothing may be written to the writer of the previous connection anymore
+                self.messages.take_offline(&previous.writer.offline());
+                Some((
+                    previous.writer,
+                    previous.session_expiry_interval > 0,
+                    vec![],
+                ))
+            }
+            // the reaper may not have purged an expired session yet, it is
+            // dropped like the session of a clean start
+            None => self
+                .offline_sessions
+                .remove(&writer.client_id)
+                .map(|session| {
+                    let resumable = !session.is_expired(SystemTime::now());
+                    (session.writer, resumable, session.queued)
+                }),
+        };
+        let session_present = match previous {
+            Some((previous, true, queued)) if !clean_start => {
+                self.topic_tree
+                    .replace_subscriber(&previous, writer.clone());
+                self.messages
+                    .retarget_copies(previous.session_id, writer.session_id);
+                // messages that were published while the session was offline
+                for message in queued {
+                    self.queue_copy(
+                        message.packet,
+                        message.expires_at,
+                        &writer,
+                        message.retained,
+                    );
+                }
+                true
+            }
+            Some((previous, ..)) => {
+                self.purge_session(&previous);
+                false
+            }
+            None => false,
+        };
         self.clients.insert(
             writer.client_id.clone(),
             Client {
                 client,
                 writer: writer.clone(),
-                should_persist,
+                session_expiry_interval,
             },
         );
         self.metrics.track_connect();
         // update all messages to point to correct writer
         // after reconnect
-        if !should_persist {}
         self.messages.update_message_publisher_refs(&writer);
-        true
+        session_present
     }
 
     #[handle_message]
-    fn disconnect(&mut self, client_id: String) {
-        self.clients.remove(&client_id);
+    fn disconnect(&mut self, writer: WriterRef) {
+        // a client that was taken over must not remove the session that replaced it
+        let client = match self.clients.get(&writer.client_id) {
+            Some(client) if client.writer.session_id == writer.session_id => {
+                self.clients.remove(&writer.client_id).unwrap()
+            }
+            _ => return,
+        };
+        if let Some(writer) = &client.writer.process {
+            self.retired_writers.push(writer.clone());
+        }
         self.metrics.track_disconnect();
+        self.end_session(client);
+    }
+
+    /// disconnect a client on behalf of an administrator. Its session is kept
+    /// like after any other disconnect. Returns false if it is not connected
+    #[handle_request]
+    fn kick(&mut self, client_id: String) -> bool {
+        match self.clients.remove(&client_id) {
+            Some(client) => {
+                self.terminate_client(&client, reason_code::ADMINISTRATIVE_ACTION);
+                self.end_session(client);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// connected clients followed by the sessions that are kept offline
+    #[handle_request]
+    fn inspect_clients(&self) -> Vec<ClientInfo> {
+        let connected = self
+            .clients
+            .values()
+            .map(|client| (&client.writer, true, Some(client.session_expiry_interval)));
+        let offline = self
+            .offline_sessions
+            .values()
+            .map(|session| (&session.writer, false, None));
+        connected
+            .chain(offline)
+            .map(|(writer, connected, session_expiry_interval)| ClientInfo {
+                client_id: writer.client_id.clone(),
+                username: writer.username.clone(),
+                protocol_version: writer.protocol_version,
+                keep_alive: writer.keep_alive,
+                connected,
+                session_expiry_interval,
+                subscriptions: self.topic_tree.subscriptions_of(writer.session_id),
+            })
+            .collect()
+    }
+
+    #[handle_request]
+    fn inspect_topics(&self) -> Vec<TopicInfo> {
+        let messages = self.messages.inspect();
+        let mut topics: Vec<TopicInfo> = self
+            .topic_tree
+            .queues()
+            .map(|queue| TopicInfo {
+                topic: queue.name.clone(),
+                queue_id: queue.id.to_string(),
+                subscribers: queue.subscribers.len(),
+                shared_groups: queue
+                    .groups
+                    .iter()
+                    .map(|group| (group.name.clone(), group.members.len()))
+                    .collect(),
+                pending_messages: messages
+                    .iter()
+                    .filter(|message| message.topic == queue.name)
+                    .count(),
+            })
+            .collect();
+        topics.sort_by(|a, b| a.topic.cmp(&b.topic));
+        topics
+    }
+
+    #[handle_request]
+    fn inspect_messages(&self) -> Vec<MessageInfo> {
+        self.messages.inspect()
+    }
+
+    #[handle_request]
+    fn inspect_wal(&self) -> WalStats {
+        self.wal.stats()
+    }
+
+    /// remove the session of a client together with its subscriptions and
+    /// the messages waiting for it. A connected client is disconnected first
+    #[handle_request]
+    fn drop_session(&mut self, client_id: String) -> bool {
+        let writer = if let Some(client) = self.clients.remove(&client_id) {
+            self.terminate_client(&client, reason_code::ADMINISTRATIVE_ACTION);
+            client.writer
+        } else if let Some(session) = self.offline_sessions.remove(&client_id) {
+            session.writer
+        } else {
+            return false;
+        };
+        self.purge_session(&writer);
+        true
+    }
+
+    /// drop every message that was published to the topic and is not
+    /// completed yet, returns the number of dropped messages
+    #[handle_request]
+    fn purge_topic(&mut self, topic: String) -> usize {
+        let now = SystemTime::now();
+        let dropped = self.messages.drop_topic_messages(&topic);
+        for (message_uuid, ctx) in dropped.iter().filter(|(_, ctx)| ctx.packet.qos > 0) {
+            self.wal.append_completion(*message_uuid, now);
+            ctx.sender
+                .reject_publish(&ctx.packet, PubackPubrecCode::ImplementationSpecificError);
+        }
+        lunatic_log::info!(
+            "[Coordinator] Purged {} messages of topic {}",
+            dropped.len(),
+            topic
+        );
+        dropped.len()
+    }
+
+    #[handle_request]
+    fn delete_retained(&mut self, topic: String) -> bool {
+        self.retained.remove(&topic)
+    }
+
+    /// publish a message with the broker as publisher, returns false if
+    /// every message id is in use
+    #[handle_request]
+    fn inject_publish(&mut self, topic: String, payload: Vec<u8>, qos: u8, retain: bool) -> bool {
+        let message_id = match qos {
+            0 => None,
+            _ => match self.messages.free_message_id() {
+                Some(id) => Some(id),
+                None => return false,
+            },
+        };
+        let packet = PublishPacket {
+            dup: false,
+            qos,
+            retain,
+            topic,
+            message_id,
+            payload,
+            properties: None,
+        };
+        lunatic_log::info!(
+            "[Coordinator] Publishing a QoS {} message to {} on behalf of an administrator",
+            qos,
+            packet.topic
+        );
+        self.publish(packet, WriterRef::broker(), SystemTime::now())
+    }
+
+    /// answers as long as the process is alive, used by the health check
+    #[handle_request]
+    fn ping(&self) -> bool {
+        true
+    }
+
+    /// stop handing out jobs to the worker. Returns true once the worker
+    /// finished the job it was working on when the draining started
+    #[handle_request]
+    fn drain(&mut self) -> bool {
+        self.draining = true;
+        self.drained
+    }
+
+    /// persist the state before the broker exits. The WAL is synced to disk
+    /// and compacted into a snapshot of the messages that are still pending
+    #[handle_request]
+    fn flush_state(&mut self) -> bool {
+        let result = self
+            .wal
+            .sync()
+            .and_then(|_| self.wal.compact(self.messages.pending()));
+        match result {
+            Ok(()) => {
+                lunatic_log::info!("[Coordinator] Flushed and compacted the WAL");
+                true
+            }
+            Err(e) => {
+                lunatic_log::error!("[Coordinator] Failed to flush the WAL | {}", e);
+                false
+            }
+        }
+    }
+
+    /// disconnect every client because the broker shuts down
+    #[handle_request]
+    fn disconnect_all(&mut self) {
+        let clients: Vec<Client> = self.clients.drain().map(|(_, client)| client).collect();
+        for client in clients {
+            self.terminate_client(&client, reason_code::SERVER_SHUTTING_DOWN);
+            self.end_session(client);
+        }
+    }
+
+    /// update the session expiry interval with the value of a v5 DISCONNECT
+    #[handle_message]
+    fn set_session_expiry(&mut self, writer: WriterRef, session_expiry_interval: u32) {
+        if let Some(client) = self.clients.get_mut(&writer.client_id) {
+            if client.writer.session_id != writer.session_id {
+                return;
+            }
+            // a session that ends with the connection cannot be extended on DISCONNECT
+            if client.session_expiry_interval == 0 && session_expiry_interval > 0 {
+                lunatic_log::info!(
+                    "[Coordinator] Client {} tried to extend a session without expiry interval",
+                    writer.client_id
+                );
+                return;
+            }
+            client.session_expiry_interval = session_expiry_interval;
+        }
     }
 
     #[handle_request]
@@ -249,20 +724,125 @@ impl CoordinatorProcess {
             reason_code: Some(0),
             properties: None,
         };
+        let version = writer.protocol_version;
+        self.acl.reload_if_changed();
+        let identifier = packet
+            .properties
+            .as_ref()
+            .and_then(|properties| properties.subscription_identifier);
+        let mut retained = vec![];
         for sub in packet.subscriptions {
-            self.topic_tree.add_subscriptions(sub.topic, writer.clone());
-            suback.granted.push(Granted::QoS2);
+            let (group, filter) = match split_shared_filter(&sub.topic) {
+                Ok(split) => split,
+                Err(e) => {
+                    lunatic_log::info!(
+                        "[Coordinator->Subscribe] Client {} sent invalid shared subscription {:?} | {:?}",
+                        writer.client_id,
+                        sub.topic,
+                        e
+                    );
+                    grant(&mut suback, version, Err(reason_code::UNSPECIFIED_ERROR));
+                    continue;
+                }
+            };
+            if let Err(e) = validate_topic_filter(filter) {
+                lunatic_log::info!(
+                    "[Coordinator->Subscribe] Client {} sent invalid topic filter {:?} | {:?}",
+                    writer.client_id,
+                    sub.topic,
+                    e
+                );
+                grant(&mut suback, version, Err(reason_code::UNSPECIFIED_ERROR));
+                continue;
+            }
+            if !self
+                .acl
+                .can_subscribe(writer.username.as_deref(), &writer.client_id, filter)
+            {
+                lunatic_log::info!(
+                    "[Coordinator->Subscribe] Client {} is not allowed to subscribe to {}",
+                    writer.client_id,
+                    sub.topic
+                );
+                grant(&mut suback, version, Err(reason_code::NOT_AUTHORIZED_V5));
+                continue;
+            }
+            let options = SubscriptionOptions::from_subscription(&sub);
+            match group {
+                // a shared subscription would never receive messages of its own members
+                Some(_) if options.no_local => {
+                    lunatic_log::info!(
+                        "[Coordinator->Subscribe] Client {} set No Local on shared subscription {}",
+                        writer.client_id,
+                        sub.topic
+                    );
+                    grant(&mut suback, version, Err(reason_code::UNSPECIFIED_ERROR));
+                    continue;
+                }
+                Some(group) => self.topic_tree.add_shared_subscription(
+                    group,
+                    filter,
+                    writer.clone(),
+                    options,
+                    identifier,
+                ),
+                None => {
+                    let matching = self.retained.matching(filter, SystemTime::now());
+                    // the subscription has to reach the queues of the retained messages
+                    for message in matching.iter() {
+                        self.topic_tree.ensure_topic_queue(&message.packet.topic);
+                    }
+                    let is_new = self.topic_tree.add_subscriptions(
+                        filter.to_owned(),
+                        writer.clone(),
+                        options,
+                        identifier,
+                    );
+                    let send_retained = match options.retain_handling {
+                        0 => true,
+                        1 => is_new,
+                        _ => false,
+                    };
+                    if send_retained {
+                        retained.extend(matching.into_iter().map(|message| (message, options.qos)));
+                    }
+                }
+            }
+            let granted = match options.qos {
+                0 => Granted::QoS0,
+                1 => Granted::QoS1,
+                _ => Granted::QoS2,
+            };
+            grant(&mut suback, version, Ok(granted));
             lunatic_log::debug!(
                 "[Coordinator->Subscribe] Got these matching queues {:?}",
                 self.topic_tree
             );
         }
+        // MQTT 3.1 has no return code for a failed subscription, the client
+        // is disconnected instead of being told that it was subscribed
+        if writer.protocol_version == 3 && suback.granted.contains(&Granted::Failure) {
+            lunatic_log::info!(
+                "[Coordinator->Subscribe] Disconnecting MQTT 3.1 client {} after a failed subscription",
+                writer.client_id
+            );
+            if let Some(client) = self.clients.remove(&writer.client_id) {
+                self.terminate_client(&client, reason_code::UNSPECIFIED_ERROR);
+                self.end_session(client);
+            }
+            return false;
+        }
         // safe to unwrap because the process is always present
         lunatic_log::debug!("Getting process {:?}", writer.process);
-        writer
-            .process
-            .unwrap()
-            .write_packet(MqttPacket::Suback(suback))
+        let process = writer.process.clone().unwrap();
+        if !process.write_packet(MqttPacket::Suback(suback)) {
+            return false;
+        }
+        // retained messages follow the SUBACK
+        for (message, granted_qos) in retained {
+            self.queue_retained(message, granted_qos, &writer);
+        }
+        true
     }
 
     #[handle_request]
@@ -272,6 +852,34 @@ impl CoordinatorProcess {
         writer: WriterRef,
         started_at: SystemTime,
     ) -> bool {
+        self.acl.reload_if_changed();
+        // publishes of the admin API are authorized by the admin token
+        if !writer.is_broker()
+            && !self
+                .acl
+                .can_publish(writer.username.as_deref(), &writer.client_id, &packet.topic)
+        {
+            lunatic_log::info!(
+                "[Coordinator->Publish] Client {} is not allowed to publish to {}",
+                writer.client_id,
+                packet.topic
+            );
+            return writer.reject_publish(&packet, PubackPubrecCode::NotAuthorized);
+        }
+        // v3 clients cannot set an expiry interval, the broker default applies to them
+        let expiry_interval = match &packet.properties {
+            Some(PublishProperties {
+                message_expiry_interval: Some(interval),
+                ..
+            }) => Some(*interval),
+            _ if writer.protocol_version != 5 => self.default_message_expiry,
+            _ => None,
+        };
+        let expires_at =
+            expiry_interval.map(|secs| SystemTime::now() + Duration::from_secs(secs as u64));
+        if packet.retain {
+            self.retained.store(&packet, expires_at);
+        }
         let message_uuid = self.messages.register_message_id(packet.message_id);
         if packet.qos > 0 {
             self.wal
@@ -283,8 +891,14 @@ impl CoordinatorProcess {
             packet.topic,
             queue
         );
-        self.messages
-            .insert_publish_message(message_uuid, packet, queue.id, writer, started_at);
+        self.messages.insert_publish_message(
+            message_uuid,
+            packet,
+            queue.id,
+            writer,
+            started_at,
+            expires_at,
+        );
         true
     }
 
@@ -292,14 +906,54 @@ impl CoordinatorProcess {
     fn confirm(&mut self, packet: ConfirmationPacket, subscriber: WriterRef) -> bool {
         // do qos 1 flow
         let message_id = packet.message_id;
-        let message_uuid = self.messages.lookup_uuid(message_id);
+        let message_uuid = match self.messages.find_uuid(message_id) {
+            Some(uuid) => uuid,
+            // a PUBREL for a message that was never stored, e.g. because it was
+            // rejected by the acl, still needs to complete the flow for the publisher
+            None if packet.cmd == PacketType::Pubrel => {
+                return match &subscriber.process {
+                    Some(process) => {
+                        process.write_packet(MqttPacket::Pubcomp(ConfirmationPacket {
+                            cmd: PacketType::Pubcomp,
+                            message_id,
+                            properties: None,
+                            puback_reason_code: None,
+                            pubcomp_reason_code: Some(PubcompPubrelCode::Success),
+                        }))
+                    }
+                    None => false,
+                };
+            }
+            None => {
+                lunatic_log::error!(
+                    "[Coordinator->Confirmation] Unknown message id {} in {:?}",
+                    message_id,
+                    packet
+                );
+                return false;
+            }
+        };
+        // a subscriber that was granted QoS 1 acknowledges its copy of a QoS 2
+        // message, that does not move the flow of the publisher forward
+        if packet.cmd == PacketType::Puback && self.messages.qos_of(message_uuid) == Some(2) {
+            return true;
+        }
         if packet.cmd == PacketType::Puback {
             return self.handle_puback(packet, message_id, message_uuid, subscriber);
         } else if packet.cmd == PacketType::Pubrel {
             // pubrel will never be sent by the subscriber, only the publisher
             return self.handle_pubrel(packet, message_id, message_uuid);
         } else if packet.cmd == PacketType::Pubrec {
-            return self.handle_pubrec(packet, message_id, message_uuid, subscriber);
+            let is_broker = self
+                .messages
+                .publisher(message_uuid)
+                .is_some_and(WriterRef::is_broker);
+            let handled = self.handle_pubrec(packet, message_id, message_uuid, subscriber);
+            // the broker does not wait for a PUBREL of its own messages
+            if is_broker {
+                self.messages.mark_to_be_released(message_id, message_uuid);
+            }
+            return handled;
         } else if packet.cmd == PacketType::Pubcomp {
             return self.handle_pubcomp(packet, message_id, message_uuid, subscriber);
         }
@@ -320,7 +974,49 @@ impl CoordinatorProcess {
 
     #[handle_request]
     fn poll_job(&mut self) -> PollResponse {
-        self.messages.poll(&mut self.topic_tree)
+        // the worker finished its previous job, nothing refers to these writers anymore
+        for writer in self.retired_writers.drain(..) {
+            writer.kill();
+        }
+        if self.draining {
+            self.drained = true;
+            return PollResponse::None;
+        }
+        let now = SystemTime::now();
+        for (message_uuid, expired) in self.messages.drop_expired(now) {
+            lunatic_log::debug!(
+                "[Coordinator->Poll] Dropping expired message {} on {}",
+                message_uuid,
+                expired.packet.topic
+            );
+            if expired.packet.qos > 0 {
+                self.wal.append_completion(message_uuid, now);
+                // the publisher is still waiting for the acknowledgement
+                expired
+                    .sender
+                    .reject_publish(&expired.packet, PubackPubrecCode::NoMatchingSubscribers);
+            }
+            self.metrics.track_expired_message();
+        }
+        self.reap_sessions(now);
+        loop {
+            let (mut job, ctx) = match self.messages.poll(&mut self.topic_tree) {
+                PollResponse::Publish(job, ctx) => (job, ctx),
+                response => return response,
+            };
+            self.queue_offline(std::mem::take(&mut job.offline), &ctx);
+            if job.queue.has_subscribers() {
+                return PollResponse::Publish(job, ctx);
+            }
+            // only offline sessions receive the message, its flow ends once it is queued for them
+            let message_uuid = job.message.message_uuid;
+            if let Some(ctx) = self.messages.drop_message(message_uuid) {
+                if ctx.packet.qos > 0 {
+                    self.wal.append_completion(message_uuid, now);
+                    ctx.sender.accept_publish(&ctx.packet);
+                }
+            }
+        }
     }
 
     #[handle_request]
@@ -347,7 +1043,13 @@ impl CoordinatorProcess {
             self.drop_inactive_subs(queue_id, inactive_subs);
         }
         lunatic_log::debug!("[Coordinator->Release] dropping message {}", id);
-        self.messages.drop_messages_by_uuid(id);
+        if qos == 2 {
+            // the PUBREL and PUBCOMP still need the context of the message
+            self.messages.drop_messages_by_uuid(id);
+        } else {
+            // the subscribers' Receive Maximum only counts messages that are still stored
+            self.messages.drop_message(id);
+        }
         true
     }
 
@@ -394,12 +1096,58 @@ impl CoordinatorProcess {
         self.messages.retry_message_later(msg, &mut self.topic_tree)
     }
 
+    /// drop a QoS 1 or 2 message that none of the subscribers can receive,
+    /// the publisher still gets its acknowledgement
+    #[handle_request]
+    fn drop_undeliverable(&mut self, Undeliverable(message_uuid): Undeliverable) -> bool {
+        let ctx = match self.messages.drop_message(message_uuid) {
+            Some(ctx) => ctx,
+            None => return false,
+        };
+        lunatic_log::debug!(
+            "[Coordinator->Undeliverable] Dropping message {} on {}",
+            message_uuid,
+            ctx.packet.topic
+        );
+        self.wal.append_completion(message_uuid, SystemTime::now());
+        ctx.sender
+            .reject_publish(&ctx.packet, PubackPubrecCode::NoMatchingSubscribers)
+    }
+
+    /// complete the flow of a QoS 1 or 2 message that no subscriber received
+    /// at its QoS, none of them will send the acknowledgement the publisher waits for
+    #[handle_request]
+    fn complete_downgraded(&mut self, Downgraded(message_uuid, inactive_subs): Downgraded) -> bool {
+        if let Some(queue_id) = self.messages.get_queue_id(message_uuid) {
+            self.drop_inactive_subs(queue_id, inactive_subs);
+        }
+        let ctx = match self.messages.drop_message(message_uuid) {
+            Some(ctx) => ctx,
+            None => return false,
+        };
+        lunatic_log::debug!(
+            "[Coordinator->Downgraded] Completing message {} on {}",
+            message_uuid,
+            ctx.packet.topic
+        );
+        self.wal.append_completion(message_uuid, SystemTime::now());
+        ctx.sender.accept_publish(&ctx.packet)
+    }
+
     #[handle_request]
     fn mark_sent(
         &mut self,
         Sent(message_id, message_uuid, qos, inactive_subs, receivers): Sent,
     ) -> bool {
         self.wal.append_sent(message_uuid, SystemTime::now());
+        // a receiver whose connection ended since the message was sent is offline
+        let receivers: Vec<Receiver> = receivers
+            .into_iter()
+            .map(|receiver| Receiver {
+                writer: self.session_writer(receiver.writer),
+                ..receiver
+            })
+            .collect();
 
         if let Some(queue_id) = self.messages.mark_sent(message_uuid, &receivers) {
             self.drop_inactive_subs(queue_id, inactive_subs);
@@ -477,6 +1225,18 @@ pub struct Cleanup(
 #[derive(Serialize, Deserialize)]
 pub struct RetryLater(pub Uuid, pub Vec<WriterRef>);
 
+/// Message that no subscriber can receive, e.g. because it is too large for all of them
+#[derive(Serialize, Deserialize)]
+pub struct Undeliverable(pub Uuid);
+
+/// Message that was only sent to subscriptions with a lower granted QoS
+#[derive(Serialize, Deserialize)]
+pub struct Downgraded(
+    pub Uuid,
+    /// vec of invalid subs that we have not been able to send messages to
+    pub Vec<WriterRef>,
+);
+
 /// Mark Message sent from to client
 #[derive(Serialize, Deserialize)]
 pub struct Sent(
@@ -489,3 +1249,282 @@ pub struct Sent(
     /// list of subs to which a message was sent
     pub Vec<Receiver>,
 );
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ban::BanSup;
+    use crate::framing;
+    use crate::metrics::MetricsSup;
+    use crate::stream::{ClientStream, Connection};
+    use crate::worker;
+    use lunatic::net::{TcpListener, TcpStream};
+    use lunatic::process::StartProcess;
+    use mqtt_packet_3_5::{ConnectPacket, DisconnectPacket, SubscribeProperties, Subscription};
+    use std::io::Write;
+
+    #[test]
+    fn suback_reason_codes() {
+        let suback = |protocol_version| {
+            let mut suback = SubackPacket {
+                granted: vec![],
+                granted_reason_codes: vec![],
+                message_id: 1,
+                reason_code: Some(0),
+                properties: None,
+            };
+            grant(&mut suback, protocol_version, Ok(Granted::QoS1));
+            grant(
+                &mut suback,
+                protocol_version,
+                Err(reason_code::NOT_AUTHORIZED_V5),
+            );
+            suback
+        };
+        let v5 = suback(5);
+        assert_eq!(v5.granted, [Granted::QoS1, Granted::Failure]);
+        assert_eq!(v5.granted_reason_codes, [0x01, 0x87]);
+        // v3 has no reason codes, a denied subscription is a plain failure
+        let v4 = suback(4);
+        assert_eq!(v4.granted, [Granted::QoS1, Granted::Failure]);
+        assert!(v4.granted_reason_codes.is_empty());
+    }
+
+    fn send(stream: &mut TcpStream, protocol_version: u8, packet: MqttPacket) {
+        let encoded = packet.encode(protocol_version).unwrap();
+        stream.write_all(&encoded).unwrap();
+    }
+
+    fn receive(stream: &mut TcpStream, protocol_version: u8) -> MqttPacket {
+        framing::read_packet(stream, usize::MAX)
+            .and_then(|raw| raw.decode(protocol_version))
+            .unwrap()
+    }
+
+    /// start the processes of the broker once for all tests
+    fn start_broker() {
+        if ProcessRef::<CoordinatorProcess>::lookup("coordinator").is_some() {
+            return;
+        }
+        MetricsSup::start_link("metrics".to_owned(), None);
+        BanSup::start_link(("bans".to_owned(), Default::default()), None);
+        CoordinatorSup::start_link(("coordinator".to_owned(), BrokerConfig::default()), None);
+        worker::worker_process();
+    }
+
+    /// connect a client process over a loopback connection, the test
+    /// plays the client on the other end
+    fn connect(listener: &TcpListener, protocol_version: u8, client_id: &str) -> TcpStream {
+        let (client, session_present) = open_session(listener, protocol_version, client_id, true);
+        assert!(!session_present);
+        client
+    }
+
+    /// connect and return whether the broker resumed a previous session
+    fn open_session(
+        listener: &TcpListener,
+        protocol_version: u8,
+        client_id: &str,
+        clean_session: bool,
+    ) -> (TcpStream, bool) {
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let (stream, peer) = listener.accept().unwrap();
+        let config = BrokerConfig::default().connection();
+        ClientProcess::start(
+            Connection::new(ClientStream::Tcp(stream), peer.ip(), config),
+            None,
+        );
+        let protocol_id = if protocol_version == 3 {
+            "MQIsdp"
+        } else {
+            "MQTT"
+        };
+        let connect = ConnectPacket {
+            protocol_id: protocol_id.to_owned(),
+            protocol_version,
+            clean_session,
+            keep_alive: 0,
+            client_id: client_id.to_owned(),
+            will: None,
+            username: None,
+            password: None,
+            properties: None,
+        };
+        send(&mut client, protocol_version, MqttPacket::Connect(connect));
+        match receive(&mut client, protocol_version) {
+            MqttPacket::Connack(connack) => (client, connack.session_present),
+            other => panic!("expected a CONNACK, got {:?}", other),
+        }
+    }
+
+    fn subscribe(
+        client: &mut TcpStream,
+        protocol_version: u8,
+        topic: &str,
+        qos: u8,
+    ) -> Vec<Granted> {
+        let subscribe = SubscribePacket {
+            message_id: 1,
+            subscriptions: vec![Subscription {
+                topic: topic.to_owned(),
+                qos,
+                nl: false,
+                rap: false,
+                rh: None,
+            }],
+            properties: None,
+        };
+        send(client, protocol_version, MqttPacket::Subscribe(subscribe));
+        match receive(client, protocol_version) {
+            MqttPacket::Suback(suback) => suback.granted,
+            other => panic!("expected a SUBACK, got {:?}", other),
+        }
+    }
+
+    #[lunatic::test]
+    fn mixed_protocol_versions() {
+        start_broker();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let versions = [3, 4, 5];
+        let mut clients: Vec<TcpStream> = versions
+            .into_iter()
+            .map(|version| connect(&listener, version, &format!("gateway-{}", version)))
+            .collect();
+        for (client, version) in clients.iter_mut().zip(versions) {
+            assert_eq!(subscribe(client, version, "gateways/+", 0), [Granted::QoS0]);
+        }
+
+        // every client receives the publishes of the others in its own protocol version
+        for publisher in 0..versions.len() {
+            let topic = format!("gateways/{}", versions[publisher]);
+            let publish = PublishPacket {
+                dup: false,
+                qos: 0,
+                retain: false,
+                topic: topic.clone(),
+                message_id: None,
+                payload: b"21.5".to_vec(),
+                properties: None,
+            };
+            send(
+                &mut clients[publisher],
+                versions[publisher],
+                MqttPacket::Publish(publish),
+            );
+            for (client, version) in clients.iter_mut().zip(versions) {
+                match receive(client, version) {
+                    MqttPacket::Publish(received) => {
+                        assert_eq!(received.topic, topic);
+                        assert_eq!(received.payload, b"21.5");
+                        assert_eq!(received.qos, 0);
+                    }
+                    other => panic!("expected a PUBLISH, got {:?}", other),
+                }
+            }
+        }
+    }
+
+    #[lunatic::test]
+    fn offline_persistent_session() {
+        start_broker();
+        let coordinator = ProcessRef::<CoordinatorProcess>::lookup("coordinator").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let publish = |client: &mut TcpStream, message_id, payload: &[u8]| {
+            let publish = PublishPacket {
+                dup: false,
+                qos: 1,
+                retain: false,
+                topic: "meters/1".to_owned(),
+                message_id: Some(message_id),
+                payload: payload.to_vec(),
+                properties: None,
+            };
+            send(client, 4, MqttPacket::Publish(publish));
+        };
+        let receive_publish = |client: &mut TcpStream, payload: &[u8]| match receive(client, 4) {
+            MqttPacket::Publish(received) => {
+                assert_eq!(received.payload, payload);
+                assert_eq!(received.qos, 1);
+                let puback = ConfirmationPacket {
+                    cmd: PacketType::Puback,
+                    message_id: received.message_id.unwrap(),
+                    puback_reason_code: None,
+                    pubcomp_reason_code: None,
+                    properties: None,
+                };
+                send(client, 4, MqttPacket::Puback(puback));
+            }
+            other => panic!("expected a PUBLISH, got {:?}", other),
+        };
+        let receive_puback = |client: &mut TcpStream, message_id| match receive(client, 4) {
+            MqttPacket::Puback(puback) => assert_eq!(puback.message_id, message_id),
+            other => panic!("expected a PUBACK, got {:?}", other),
+        };
+
+        let (mut offline, _) = open_session(&listener, 4, "meter-offline", false);
+        assert_eq!(subscribe(&mut offline, 4, "meters/+", 1), [Granted::QoS1]);
+        let disconnect = DisconnectPacket {
+            reason_code: None,
+            properties: None,
+        };
+        send(&mut offline, 4, MqttPacket::Disconnect(disconnect));
+        while coordinator
+            .inspect_clients()
+            .iter()
+            .any(|client| client.client_id == "meter-offline" && client.connected)
+        {
+            lunatic::sleep(Duration::from_millis(10));
+        }
+        let mut publisher = connect(&listener, 4, "meter-publisher");
+
+        // the only subscriber is offline, the message is queued in its session
+        publish(&mut publisher, 1, b"1");
+        receive_puback(&mut publisher, 1);
+
+        // an online subscriber still receives the messages right away
+        let mut online = connect(&listener, 4, "meter-online");
+        assert_eq!(subscribe(&mut online, 4, "meters/+", 1), [Granted::QoS1]);
+        publish(&mut publisher, 2, b"2");
+        receive_publish(&mut online, b"2");
+        receive_puback(&mut publisher, 2);
+
+        // the resumed session receives everything it missed
+        let (mut resumed, session_present) = open_session(&listener, 4, "meter-offline", false);
+        assert!(session_present);
+        receive_publish(&mut resumed, b"1");
+        receive_publish(&mut resumed, b"2");
+    }
+
+    #[lunatic::test]
+    fn subscription_identifier_zero() {
+        start_broker();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = connect(&listener, 5, "identifier-zero");
+        let subscribe = SubscribePacket {
+            message_id: 1,
+            subscriptions: vec![Subscription {
+                topic: "meters/+".to_owned(),
+                qos: 1,
+                nl: false,
+                rap: false,
+                rh: None,
+            }],
+            properties: Some(SubscribeProperties {
+                subscription_identifier: Some(0),
+                user_properties: None,
+            }),
+        };
+        send(&mut client, 5, MqttPacket::Subscribe(subscribe));
+        // a protocol error closes the connection instead of failing the subscription
+        match receive(&mut client, 5) {
+            MqttPacket::Disconnect(disconnect) => {
+                assert_eq!(disconnect.reason_code, Some(reason_code::PROTOCOL_ERROR))
+            }
+            other => panic!("expected a DISCONNECT, got {:?}", other),
+        }
+    }
+}
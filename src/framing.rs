@@ -0,0 +1,177 @@
+use mqtt_packet_3_5::{MqttPacket, PacketDecoder};
+use std::io::{Cursor, Error as IoError, Read};
+
+/// default maximum packet size. Packets are read into memory completely,
+/// so the limit has to stay well below the memory limit of a client process
+pub const MAX_PACKET_SIZE: usize = 1024 * 1024;
+
+const CONNECT: u8 = 1;
+const PUBLISH: u8 = 3;
+const PUBREL: u8 = 6;
+const SUBSCRIBE: u8 = 8;
+const UNSUBSCRIBE: u8 = 10;
+const AUTH: u8 = 15;
+
+#[derive(Debug)]
+pub enum FrameError {
+    /// the connection was closed or could not be read from
+    Closed(IoError),
+    /// the packet violates the protocol and cannot be decoded
+    Malformed(String),
+    /// the packet is larger than allowed, carries the announced size
+    TooLarge(usize),
+}
+
+/// A complete packet as it was read from the stream
+#[derive(Debug)]
+pub struct RawPacket {
+    /// first byte of the fixed header with packet type and flags
+    pub header: u8,
+    /// length of the fixed header, the variable header starts at this offset
+    pub header_len: usize,
+    /// the whole packet including the fixed header
+    pub bytes: Vec<u8>,
+}
+
+impl RawPacket {
+    pub fn packet_type(&self) -> u8 {
+        self.header >> 4
+    }
+
+    /// variable header and payload
+    pub fn body(&self) -> &[u8] {
+        &self.bytes[self.header_len..]
+    }
+
+    pub fn decode(self, protocol_version: u8) -> Result<MqttPacket, FrameError> {
+        PacketDecoder::from_stream(Cursor::new(self.bytes))
+            .decode_packet(protocol_version)
+            .map_err(|e| FrameError::Malformed(format!("{:?}", e)))
+    }
+}
+
+/// read the next packet from the stream. The size announced in the fixed
+/// header is checked before the rest of the packet is read, so an oversized
+/// packet never ends up in memory
+pub fn read_packet<R: Read>(reader: &mut R, max_size: usize) -> Result<RawPacket, FrameError> {
+    let mut bytes = vec![0u8];
+    reader
+        .read_exact(&mut bytes[..1])
+        .map_err(FrameError::Closed)?;
+    let header = bytes[0];
+    validate_flags(header)?;
+
+    // remaining length is encoded in up to four bytes
+    let mut remaining_length = 0usize;
+    let mut multiplier = 1usize;
+    loop {
+        let mut byte = [0u8];
+        reader.read_exact(&mut byte).map_err(FrameError::Closed)?;
+        bytes.push(byte[0]);
+        remaining_length += (byte[0] & 0x7F) as usize * multiplier;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+        if bytes.len() > 4 {
+            return Err(FrameError::Malformed(
+                "Remaining length exceeds four bytes".to_owned(),
+            ));
+        }
+        multiplier *= 128;
+    }
+    let header_len = bytes.len();
+    let size = header_len + remaining_length;
+    if size > max_size {
+        return Err(FrameError::TooLarge(size));
+    }
+
+    bytes.resize(size, 0);
+    reader
+        .read_exact(&mut bytes[header_len..])
+        .map_err(FrameError::Closed)?;
+    Ok(RawPacket {
+        header,
+        header_len,
+        bytes,
+    })
+}
+
+/// check the reserved flags of the fixed header
+fn validate_flags(header: u8) -> Result<(), FrameError> {
+    let flags = header & 0x0F;
+    let valid = match header >> 4 {
+        0 => false,
+        // QoS 3 is not allowed
+        PUBLISH => (flags >> 1) & 0x03 != 3,
+        PUBREL | SUBSCRIBE | UNSUBSCRIBE => flags == 0b0010,
+        CONNECT..=AUTH => flags == 0,
+        _ => false,
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(FrameError::Malformed(format!(
+            "Invalid fixed header {:#010b}",
+            header
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_complete_packets() {
+        // PINGREQ followed by a PUBLISH with a two byte body
+        let mut stream = Cursor::new(vec![0xC0, 0x00, 0x30, 0x02, 0x00, 0x00]);
+        let ping = read_packet(&mut stream, 100).unwrap();
+        assert_eq!(ping.packet_type(), 12);
+        assert_eq!(ping.bytes, vec![0xC0, 0x00]);
+        let publish = read_packet(&mut stream, 100).unwrap();
+        assert_eq!(publish.packet_type(), 3);
+        assert_eq!(publish.body(), &[0x00, 0x00]);
+        assert!(matches!(
+            read_packet(&mut stream, 100),
+            Err(FrameError::Closed(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_reserved_flags() {
+        // SUBSCRIBE has to set the flags to 0010
+        let mut stream = Cursor::new(vec![0x80, 0x00]);
+        assert!(matches!(
+            read_packet(&mut stream, 100),
+            Err(FrameError::Malformed(_))
+        ));
+        // PUBLISH with QoS 3
+        let mut stream = Cursor::new(vec![0x36, 0x00]);
+        assert!(matches!(
+            read_packet(&mut stream, 100),
+            Err(FrameError::Malformed(_))
+        ));
+        // reserved packet type 0
+        let mut stream = Cursor::new(vec![0x00, 0x00]);
+        assert!(matches!(
+            read_packet(&mut stream, 100),
+            Err(FrameError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn rejects_large_packets_before_reading_them() {
+        // announces 16384 bytes but does not send them
+        let mut stream = Cursor::new(vec![0x30, 0x80, 0x80, 0x01]);
+        assert!(matches!(
+            read_packet(&mut stream, 1024),
+            Err(FrameError::TooLarge(16388))
+        ));
+        // remaining length with more than four bytes
+        let mut stream = Cursor::new(vec![0x30, 0xFF, 0xFF, 0xFF, 0xFF, 0x7F]);
+        assert!(matches!(
+            read_packet(&mut stream, usize::MAX),
+            Err(FrameError::Malformed(_))
+        ));
+    }
+}
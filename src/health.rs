@@ -0,0 +1,234 @@
+//! Liveness and readiness of the broker for the `/healthz` and `/readyz` endpoints
+use crate::coordinator::{CoordinatorProcess, CoordinatorProcessHandler};
+use crate::metrics::{MetricsProcess, MetricsProcessHandler};
+use crate::shutdown::{ShutdownProcess, ShutdownProcessHandler};
+use lunatic::{abstract_process, process::ProcessRef, sleep, supervisor::Supervisor};
+use lunatic::{Mailbox, Process};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::{Duration, SystemTime};
+
+/// a subsystem that does not answer within this time is down. It is shorter
+/// than the default timeout of a kubernetes probe
+const PING_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// how often the answer to a ping is checked
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The `HealthSup` is supervising one global instance of the `HealthProcess`.
+pub struct HealthSup;
+impl Supervisor for HealthSup {
+    type Arg = (String, Vec<String>);
+    type Children = HealthProcess;
+
+    fn init(
+        config: &mut lunatic::supervisor::SupervisorConfig<Self>,
+        (name, listeners): Self::Arg,
+    ) {
+        config.children_args((listeners, Some(name)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Up,
+    Down,
+}
+
+impl From<bool> for Status {
+    fn from(up: bool) -> Status {
+        if up {
+            Status::Up
+        } else {
+            Status::Down
+        }
+    }
+}
+
+/// The broker is up if every subsystem is up
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HealthReport {
+    pub status: Status,
+    pub subsystems: BTreeMap<String, Status>,
+}
+
+impl HealthReport {
+    pub fn new(subsystems: BTreeMap<String, Status>) -> HealthReport {
+        let up = subsystems.values().all(|status| *status == Status::Up);
+        HealthReport {
+            status: up.into(),
+            subsystems,
+        }
+    }
+
+    pub fn is_up(&self) -> bool {
+        self.status == Status::Up
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+enum Subsystem {
+    Coordinator,
+    Metrics,
+}
+
+/// Keeps track of the listeners that are bound to their port and of the
+/// answers to the pings of the health checks
+pub struct HealthProcess {
+    /// listeners that are enabled in the config
+    listeners: Vec<String>,
+    bound: HashSet<String>,
+    /// pings that were started, with the answer once it arrived
+    pings: HashMap<u64, Option<bool>>,
+    next_ping: u64,
+}
+
+#[abstract_process(visibility = pub)]
+impl HealthProcess {
+    /// function that retrieves the running health process
+    pub fn get_process() -> ProcessRef<HealthProcess> {
+        ProcessRef::<HealthProcess>::lookup("health").unwrap()
+    }
+
+    #[init]
+    fn init(_: ProcessRef<Self>, listeners: Vec<String>) -> Self {
+        HealthProcess {
+            listeners,
+            bound: HashSet::new(),
+            pings: HashMap::new(),
+            next_ping: 0,
+        }
+    }
+
+    #[handle_message]
+    fn listener_bound(&mut self, name: String) {
+        self.bound.insert(name);
+    }
+
+    #[handle_request]
+    fn listeners(&self) -> BTreeMap<String, Status> {
+        self.listeners
+            .iter()
+            .map(|name| {
+                (
+                    format!("listener_{}", name),
+                    self.bound.contains(name).into(),
+                )
+            })
+            .collect()
+    }
+
+    #[handle_request]
+    fn start_ping(&mut self) -> u64 {
+        self.next_ping += 1;
+        self.pings.insert(self.next_ping, None);
+        self.next_ping
+    }
+
+    /// an answer that arrives after the health check gave up is ignored
+    #[handle_message]
+    fn answer_ping(&mut self, id: u64, up: bool) {
+        if let Some(answer) = self.pings.get_mut(&id) {
+            *answer = Some(up);
+        }
+    }
+
+    /// the answer to a ping, it is forgotten once it was returned
+    #[handle_request]
+    fn ping_answer(&mut self, id: u64) -> Option<bool> {
+        let answer = self.pings.get(&id).copied().flatten();
+        if answer.is_some() {
+            self.pings.remove(&id);
+        }
+        answer
+    }
+
+    #[handle_message]
+    fn give_up_ping(&mut self, id: u64) {
+        self.pings.remove(&id);
+    }
+}
+
+/// ping a subsystem from a separate process, so that a subsystem that is
+/// stuck makes the health check fail instead of blocking it
+fn responds(subsystem: Subsystem) -> bool {
+    let health = match ProcessRef::<HealthProcess>::lookup("health") {
+        Some(health) => health,
+        None => return false,
+    };
+    let id = health.start_ping();
+    Process::spawn((id, subsystem), |(id, subsystem), _: Mailbox<()>| {
+        let up = match subsystem {
+            Subsystem::Coordinator => ProcessRef::<CoordinatorProcess>::lookup("coordinator")
+                .is_some_and(|coordinator| coordinator.ping()),
+            Subsystem::Metrics => ProcessRef::<MetricsProcess>::lookup("metrics")
+                .is_some_and(|metrics| metrics.ping()),
+        };
+        HealthProcess::get_process().answer_ping(id, up);
+    });
+    let deadline = SystemTime::now() + PING_TIMEOUT;
+    while SystemTime::now() < deadline {
+        if let Some(up) = health.ping_answer(id) {
+            return up;
+        }
+        sleep(POLL_INTERVAL);
+    }
+    lunatic_log::error!(
+        "[Health] {:?} did not answer within {:?}",
+        subsystem,
+        PING_TIMEOUT
+    );
+    health.give_up_ping(id);
+    false
+}
+
+/// the coordinator recovers the WAL in its init, it is only registered and
+/// able to answer once the recovery finished
+fn coordinator_up() -> bool {
+    responds(Subsystem::Coordinator)
+}
+
+/// the process is up and the coordinator and metrics processes respond in time
+pub fn liveness() -> HealthReport {
+    let metrics_up = responds(Subsystem::Metrics);
+    HealthReport::new(BTreeMap::from([
+        ("coordinator".to_owned(), coordinator_up().into()),
+        ("metrics".to_owned(), metrics_up.into()),
+    ]))
+}
+
+/// the WAL was recovered and every listener is bound. A broker that is
+/// shutting down does not accept connections and is not ready anymore
+pub fn readiness() -> HealthReport {
+    let mut subsystems = match ProcessRef::<HealthProcess>::lookup("health") {
+        Some(health) => health.listeners(),
+        None => BTreeMap::from([("listeners".to_owned(), Status::Down)]),
+    };
+    subsystems.insert("wal_recovery".to_owned(), coordinator_up().into());
+    let accepting = ProcessRef::<ShutdownProcess>::lookup("shutdown")
+        .is_some_and(|shutdown| shutdown.is_accepting());
+    subsystems.insert("accepting_connections".to_owned(), accepting.into());
+    HealthReport::new(subsystems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_status() {
+        let report = HealthReport::new(BTreeMap::from([
+            ("coordinator".to_owned(), Status::Up),
+            ("metrics".to_owned(), Status::Up),
+        ]));
+        assert!(report.is_up());
+
+        let report = HealthReport::new(BTreeMap::from([
+            ("wal_recovery".to_owned(), Status::Up),
+            ("listener_tcp".to_owned(), Status::Down),
+        ]));
+        assert!(!report.is_up());
+        assert_eq!(report.subsystems["listener_tcp"], Status::Down);
+    }
+}
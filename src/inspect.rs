@@ -1,11 +1,12 @@
-use crate::metrics::MetricsProcess;
-use lunatic::{
-    host,
-    process::{AbstractProcess, ProcessRef, ProcessRequest, Request, StartProcess},
-    supervisor::Supervisor,
-    Process,
-};
+//! The broker state and the actions of the JSON admin API
+use crate::coordinator::{CoordinatorProcess, CoordinatorProcessHandler};
+use crate::persistence::WalStats;
+use crate::structure::{PublishMessage, ReleaseMessage};
+use crate::topic_tree::validate_topic_name;
+use lunatic::{abstract_process, host, process::ProcessRef, supervisor::Supervisor};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use uuid::Uuid;
 
 /// The `InspectSup` is supervising one global instance of the `InspectProcess`.
 pub struct InspectSup;
@@ -19,22 +20,289 @@ impl Supervisor for InspectSup {
     }
 }
 
-/// this process will handle web UI interactions
-/// which should allow a user to send messages and inspect queues etc.
+/// A connected client or a session that is kept after its client disconnected
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ClientInfo {
+    pub client_id: String,
+    pub username: Option<String>,
+    pub protocol_version: u8,
+    pub keep_alive: u16,
+    pub connected: bool,
+    pub session_expiry_interval: Option<u32>,
+    /// topics the session receives messages of, shared subscriptions are
+    /// listed as `$share/<group>/<topic>`
+    pub subscriptions: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TopicInfo {
+    pub topic: String,
+    pub queue_id: String,
+    pub subscribers: usize,
+    /// number of members of every shared subscription group
+    pub shared_groups: BTreeMap<String, usize>,
+    pub pending_messages: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageState {
+    /// waiting for the worker to send it to the subscribers
+    Queued,
+    /// picked up by the worker or an acknowledgement is being forwarded
+    InProgress,
+    AwaitingPuback,
+    AwaitingPubrec,
+    AwaitingPubrel,
+}
+
+/// where a message is in its QoS flow. `publish` is the entry of the
+/// message in the queue and `release` tracks the PUBREC and PUBREL of QoS 2
+pub fn message_state(
+    qos: u8,
+    publish: Option<&PublishMessage>,
+    release: Option<&ReleaseMessage>,
+) -> MessageState {
+    if let Some(release) = release {
+        return match (release.pubrec_received, release.pubrel_received) {
+            (true, true) => MessageState::InProgress,
+            (true, false) => MessageState::AwaitingPubrel,
+            (false, _) => MessageState::AwaitingPubrec,
+        };
+    }
+    match publish {
+        Some(publish) if publish.sent => match qos {
+            1 => MessageState::AwaitingPuback,
+            2 => MessageState::AwaitingPubrec,
+            _ => MessageState::InProgress,
+        },
+        Some(publish) if !publish.in_progress => MessageState::Queued,
+        _ => MessageState::InProgress,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MessageInfo {
+    pub uuid: Uuid,
+    pub topic: String,
+    pub qos: u8,
+    pub message_id: Option<u16>,
+    pub publisher: String,
+    pub state: MessageState,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct MessagesReport {
+    pub counts: BTreeMap<MessageState, usize>,
+    pub messages: Vec<MessageInfo>,
+}
+
+impl MessagesReport {
+    pub fn new(mut messages: Vec<MessageInfo>) -> MessagesReport {
+        messages.sort_by_key(|message| (message.state, message.topic.clone()));
+        let mut counts = BTreeMap::new();
+        for message in messages.iter() {
+            *counts.entry(message.state).or_default() += 1;
+        }
+        MessagesReport { counts, messages }
+    }
+}
+
+/// A test message that is published through the admin API
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AdminPublish {
+    pub topic: String,
+    #[serde(default)]
+    pub payload: String,
+    #[serde(default)]
+    pub qos: u8,
+    #[serde(default)]
+    pub retain: bool,
+}
+
+impl AdminPublish {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.qos > 2 {
+            return Err(format!("invalid QoS {}", self.qos));
+        }
+        validate_topic_name(&self.topic).map_err(|e| format!("invalid topic: {:?}", e))
+    }
+}
+
+/// Answers the requests of the admin API with snapshots of the coordinator
+/// state and forwards the admin actions to it
 pub struct InspectProcess {
-    metrics: ProcessRef<MetricsProcess>,
+    coordinator: ProcessRef<CoordinatorProcess>,
 }
 
-impl AbstractProcess for InspectProcess {
-    type Arg = ();
-    type State = Self;
+#[abstract_process(visibility = pub)]
+impl InspectProcess {
+    /// function that retrieves the running inspect process
+    pub fn get_process() -> ProcessRef<InspectProcess> {
+        ProcessRef::<InspectProcess>::lookup("inspect").unwrap()
+    }
 
-    fn init(_: ProcessRef<Self>, _: Self::Arg) -> Self::State {
+    #[init]
+    fn init(_: ProcessRef<Self>, _: ()) -> Self {
         // Inspector shouldn't die when a client dies. This makes the link one-directional.
         unsafe { host::api::process::die_when_link_dies(0) };
 
-        let metrics = MetricsProcess::get_process();
+        let coordinator = ProcessRef::<CoordinatorProcess>::lookup("coordinator").unwrap();
+        InspectProcess { coordinator }
+    }
+
+    #[handle_request]
+    fn clients(&self) -> Vec<ClientInfo> {
+        self.coordinator.inspect_clients()
+    }
+
+    #[handle_request]
+    fn topics(&self) -> Vec<TopicInfo> {
+        self.coordinator.inspect_topics()
+    }
+
+    #[handle_request]
+    fn messages(&self) -> MessagesReport {
+        MessagesReport::new(self.coordinator.inspect_messages())
+    }
+
+    #[handle_request]
+    fn wal(&self) -> WalStats {
+        self.coordinator.inspect_wal()
+    }
+
+    #[handle_request]
+    fn kick_client(&self, client_id: String) -> bool {
+        lunatic_log::info!("[Inspect] Disconnecting client {}", client_id);
+        self.coordinator.kick(client_id)
+    }
+
+    #[handle_request]
+    fn drop_session(&self, client_id: String) -> bool {
+        lunatic_log::info!("[Inspect] Dropping the session of client {}", client_id);
+        self.coordinator.drop_session(client_id)
+    }
+
+    #[handle_request]
+    fn purge_topic(&self, topic: String) -> usize {
+        self.coordinator.purge_topic(topic)
+    }
+
+    #[handle_request]
+    fn delete_retained(&self, topic: String) -> bool {
+        lunatic_log::info!("[Inspect] Deleting the retained message of {}", topic);
+        self.coordinator.delete_retained(topic)
+    }
+
+    /// publish a test message, returns false if it could not be queued
+    #[handle_request]
+    fn publish(&self, message: AdminPublish) -> Result<bool, String> {
+        message.validate()?;
+        Ok(self.coordinator.inject_publish(
+            message.topic,
+            message.payload.into_bytes(),
+            message.qos,
+            message.retain,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn publish(in_progress: bool, sent: bool) -> PublishMessage {
+        PublishMessage {
+            message_uuid: Uuid::new_v4(),
+            message_id: Some(1),
+            queue_id: 0,
+            in_progress,
+            sent,
+        }
+    }
+
+    fn release(pubrec_received: bool, pubrel_received: bool) -> ReleaseMessage {
+        ReleaseMessage {
+            message_id: 1,
+            message_uuid: Uuid::new_v4(),
+            pubrec_received,
+            pubrel_received,
+        }
+    }
+
+    #[test]
+    fn message_states() {
+        let queued = publish(false, false);
+        assert_eq!(message_state(1, Some(&queued), None), MessageState::Queued);
+        let picked = publish(true, false);
+        assert_eq!(
+            message_state(1, Some(&picked), None),
+            MessageState::InProgress
+        );
+        let sent = publish(true, true);
+        assert_eq!(
+            message_state(1, Some(&sent), None),
+            MessageState::AwaitingPuback
+        );
+        assert_eq!(
+            message_state(2, Some(&sent), None),
+            MessageState::AwaitingPubrec
+        );
+        // the publish entry is dropped once the subscriber sent PUBREC
+        assert_eq!(
+            message_state(2, None, Some(&release(true, false))),
+            MessageState::AwaitingPubrel
+        );
+        assert_eq!(
+            message_state(2, Some(&sent), Some(&release(false, true))),
+            MessageState::AwaitingPubrec
+        );
+        assert_eq!(
+            message_state(2, None, Some(&release(true, true))),
+            MessageState::InProgress
+        );
+    }
+
+    #[test]
+    fn validate_admin_publish() {
+        let message = AdminPublish {
+            topic: "sensors/1".to_owned(),
+            payload: "21".to_owned(),
+            qos: 1,
+            retain: false,
+        };
+        assert_eq!(message.validate(), Ok(()));
+        let invalid_qos = AdminPublish {
+            qos: 3,
+            ..message.clone()
+        };
+        assert!(invalid_qos.validate().is_err());
+        let wildcard = AdminPublish {
+            topic: "sensors/+".to_owned(),
+            ..message
+        };
+        assert!(wildcard.validate().is_err());
+    }
 
-        InspectProcess { metrics }
+    #[test]
+    fn report_counts() {
+        let message = |topic: &str, state| MessageInfo {
+            uuid: Uuid::new_v4(),
+            topic: topic.to_owned(),
+            qos: 1,
+            message_id: None,
+            publisher: "publisher".to_owned(),
+            state,
+        };
+        let report = MessagesReport::new(vec![
+            message("b", MessageState::Queued),
+            message("a", MessageState::AwaitingPuback),
+            message("a", MessageState::Queued),
+        ]);
+        assert_eq!(report.counts[&MessageState::Queued], 2);
+        assert_eq!(report.counts[&MessageState::AwaitingPuback], 1);
+        assert!(!report.counts.contains_key(&MessageState::InProgress));
+        assert_eq!(report.messages[0].topic, "a");
+        assert_eq!(report.messages[2].state, MessageState::AwaitingPuback);
     }
 }
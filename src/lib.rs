@@ -1,13 +1,28 @@
+pub mod acl;
+pub mod auth;
+pub mod ban;
 // pub mod broker;
 // pub mod queue;
 pub mod coordinator;
 // pub mod session;
 pub mod client;
-pub mod metrics_server;
-// pub mod inspect;
+pub mod config;
+pub mod connect;
+pub mod framing;
+pub mod health;
+pub mod inspect;
+pub mod listener;
 pub mod message_store;
 pub mod metrics;
+pub mod metrics_server;
 pub mod persistence;
+pub mod reason_code;
+pub mod retained;
+pub mod scram;
+pub mod shutdown;
+pub mod stream;
 pub mod structure;
+pub mod topic_alias;
 pub mod topic_tree;
+pub mod websocket;
 pub mod worker;
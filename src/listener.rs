@@ -0,0 +1,146 @@
+use crate::ban::{BanProcess, BanProcessHandler, Peer};
+use crate::client::ClientProcess;
+use crate::config::{ConnectionConfig, TlsConfig, WebSocketConfig};
+use crate::health::{HealthProcess, HealthProcessHandler};
+use crate::shutdown::{ShutdownProcess, ShutdownProcessHandler};
+use crate::stream::{ClientStream, Connection};
+use crate::websocket::WsStream;
+use lunatic::net::{TcpListener, TlsListener};
+use lunatic::{process::StartProcess, Mailbox, Process, ProcessConfig};
+use std::fs;
+
+/// Limit client's memory usage to 5 Mb & allow sub-processes.
+fn client_config() -> ProcessConfig {
+    let mut client_conf = ProcessConfig::new().expect("create process config");
+    client_conf.set_max_memory(5_000_000);
+    client_conf.set_can_spawn_processes(true);
+    client_conf
+}
+
+/// start the plain MQTT listener in a separate process
+pub fn start_tcp(port: u16, connection: ConnectionConfig) {
+    Process::spawn_link((port, connection), |(port, connection), _: Mailbox<()>| {
+        let address = format!("0.0.0.0:{}", port);
+        let listener = TcpListener::bind(address).unwrap();
+        lunatic_log::info!("Started server on port {}", port);
+        HealthProcess::get_process().listener_bound("tcp".to_owned());
+
+        let client_conf = client_config();
+        let bans = BanProcess::get_process();
+        let shutdown = ShutdownProcess::get_process();
+        while let Ok((stream, addr)) = listener.accept() {
+            if !shutdown.is_accepting() {
+                continue;
+            }
+            if !bans.connect_attempt(Peer::Ip(addr.ip())) {
+                lunatic_log::debug!("Refused connection from banned address {}", addr);
+                continue;
+            }
+            ClientProcess::start_config(
+                Connection::new(ClientStream::Tcp(stream), addr.ip(), connection.clone()),
+                None,
+                &client_conf,
+            );
+        }
+    });
+}
+
+/// start the MQTTS listener in a separate process
+pub fn start_tls(config: TlsConfig, connection: ConnectionConfig) {
+    Process::spawn_link(
+        (config, connection),
+        |(config, connection), _: Mailbox<()>| {
+            let certs = match fs::read_to_string(&config.cert_chain) {
+                Ok(certs) => certs,
+                Err(e) => {
+                    lunatic_log::error!(
+                        "[TLS] Failed to read certificate chain {} | {:?}",
+                        config.cert_chain,
+                        e
+                    );
+                    return;
+                }
+            };
+            let key = match fs::read_to_string(&config.private_key) {
+                Ok(key) => key,
+                Err(e) => {
+                    lunatic_log::error!(
+                        "[TLS] Failed to read private key {} | {:?}",
+                        config.private_key,
+                        e
+                    );
+                    return;
+                }
+            };
+            let address = format!("0.0.0.0:{}", config.port);
+            let listener = TlsListener::bind(address, certs, key).unwrap();
+            lunatic_log::info!("Started TLS server on port {}", config.port);
+            HealthProcess::get_process().listener_bound("tls".to_owned());
+
+            let client_conf = client_config();
+            let bans = BanProcess::get_process();
+            let shutdown = ShutdownProcess::get_process();
+            while let Ok((stream, addr)) = listener.accept() {
+                // dropping the stream closes the connection
+                if !shutdown.is_accepting() {
+                    continue;
+                }
+                if !bans.connect_attempt(Peer::Ip(addr.ip())) {
+                    lunatic_log::debug!("[TLS] Refused connection from banned address {}", addr);
+                    continue;
+                }
+                ClientProcess::start_config(
+                    Connection::new(ClientStream::Tls(stream), addr.ip(), connection.clone()),
+                    None,
+                    &client_conf,
+                );
+            }
+        },
+    );
+}
+
+/// start the MQTT over WebSockets listener in a separate process. The
+/// websocket handshake is done by the client process so that a slow
+/// client cannot block the listener
+pub fn start_websocket(config: WebSocketConfig, connection: ConnectionConfig) {
+    Process::spawn_link(
+        (config, connection),
+        |(config, connection), _: Mailbox<()>| {
+            let address = format!("0.0.0.0:{}", config.port);
+            let listener = TcpListener::bind(address).unwrap();
+            lunatic_log::info!(
+                "Started WebSocket server on port {} with path {}",
+                config.port,
+                config.path
+            );
+            HealthProcess::get_process().listener_bound("websocket".to_owned());
+
+            let client_conf = client_config();
+            let bans = BanProcess::get_process();
+            let shutdown = ShutdownProcess::get_process();
+            while let Ok((stream, addr)) = listener.accept() {
+                // dropping the stream closes the connection
+                if !shutdown.is_accepting() {
+                    continue;
+                }
+                if !bans.connect_attempt(Peer::Ip(addr.ip())) {
+                    lunatic_log::debug!(
+                        "[WebSocket] Refused connection from banned address {}",
+                        addr
+                    );
+                    continue;
+                }
+                let stream = ClientStream::WebSocket(WsStream::new(
+                    stream,
+                    config.path.clone(),
+                    connection.max_packet_size,
+                ));
+                ClientProcess::start_config(
+                    Connection::new(stream, addr.ip(), connection.clone()),
+                    None,
+                    &client_conf,
+                );
+            }
+        },
+    );
+}
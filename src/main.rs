@@ -1,35 +1,59 @@
-use lunatic::{net::TcpListener, process::StartProcess, ProcessConfig};
+use lunatic::process::StartProcess;
 use lunatic_log::subscriber::fmt::FmtSubscriber;
 use lunatic_log::LevelFilter;
-use mqtt_broker::client::ClientProcess;
+use mqtt_broker::ban::BanSup;
+use mqtt_broker::config::BrokerConfig;
 use mqtt_broker::coordinator::CoordinatorSup;
+use mqtt_broker::health::HealthSup;
+use mqtt_broker::inspect::InspectSup;
 use mqtt_broker::metrics::MetricsSup;
+use mqtt_broker::shutdown::{self, ShutdownSup};
 // use mqtt_broker::metrics_server;
-use mqtt_broker::{metrics_server, worker};
+use mqtt_broker::{listener, metrics_server, worker};
 
 fn main() {
     lunatic_log::init(FmtSubscriber::new(LevelFilter::Info).pretty());
+    let config = match BrokerConfig::load() {
+        Ok(config) => config,
+        Err(e) => {
+            lunatic_log::error!("[Config] The broker was not started | {}", e);
+            return;
+        }
+    };
     // Create a coordinator supervisor and register the coordinator under the "coordinator" name.
     MetricsSup::start_link("metrics".to_owned(), None);
-    CoordinatorSup::start_link("coordinator".to_owned(), None);
+    BanSup::start_link(("bans".to_owned(), config.ban.clone()), None);
+    CoordinatorSup::start_link(("coordinator".to_owned(), config.clone()), None);
+    InspectSup::start_link("inspect".to_owned(), None);
+    ShutdownSup::start_link(("shutdown".to_owned(), config.shutdown_timeout_secs), None);
+
+    // the broker is ready once every enabled listener is bound
+    let mut listeners = vec!["tcp".to_owned()];
+    if config.tls.is_some() {
+        listeners.push("tls".to_owned());
+    }
+    if config.websocket.is_some() {
+        listeners.push("websocket".to_owned());
+    }
+    HealthSup::start_link(("health".to_owned(), listeners), None);
 
     // start single worker
     worker::worker_process();
 
-    let port = 1883;
-    lunatic_log::info!("Started server on port {}", port);
-    let address = format!("0.0.0.0:{}", port);
-    let listener = TcpListener::bind(address).unwrap();
-
     // start http endpoint
-    metrics_server::start_server();
+    metrics_server::start_server(config.http_port, config.admin_token.clone());
 
-    // Limit client's memory usage to 5 Mb & allow sub-processes.
-    let mut client_conf = ProcessConfig::new().expect("create process config");
-    client_conf.set_max_memory(5_000_000);
-    client_conf.set_can_spawn_processes(true);
-
-    while let Ok((stream, _)) = listener.accept() {
-        ClientProcess::start_config(stream, None, &client_conf);
+    let connection = config.connection();
+    if let Some(tls) = config.tls {
+        listener::start_tls(tls, connection.clone());
+    }
+    if let Some(websocket) = config.websocket {
+        listener::start_websocket(websocket, connection.clone());
     }
+
+    listener::start_tcp(config.port, connection);
+
+    // the broker runs until `run_server.sh` receives SIGTERM or SIGINT and
+    // requests the shutdown, or it is requested through `POST /shutdown`
+    shutdown::wait(config.shutdown_timeout_secs);
 }
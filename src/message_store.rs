@@ -1,8 +1,9 @@
 use std::collections::HashMap;
 
 use crate::coordinator::{PollResponse, RetryLater};
+use crate::inspect::{message_state, MessageInfo};
 use crate::structure::{
-    CompletionMessage, ConfirmationMessage, PublishContext, PublishJob, PublishMessage,
+    CompletionMessage, ConfirmationMessage, CopyTarget, PublishContext, PublishJob, PublishMessage,
     QueueMessage, Receiver, ReleaseMessage, WriterRef,
 };
 use crate::topic_tree::TopicTree;
@@ -10,6 +11,10 @@ use mqtt_packet_3_5::{ConfirmationPacket, PublishPacket};
 use std::time::SystemTime;
 use uuid::Uuid;
 
+fn free_message_id(message_ids: &HashMap<u16, Uuid>) -> Option<u16> {
+    (1..=u16::MAX).find(|id| !message_ids.contains_key(id))
+}
+
 #[derive(Debug)]
 pub struct MessageStore {
     message_queue: Vec<QueueMessage>,
@@ -152,7 +157,7 @@ impl MessageStore {
     ) -> bool {
         return !confirm.in_progress
             && !waiting_qos1.contains_key(&confirm.message_id)
-            && confirm.publisher.process.is_some();
+            && confirm.publisher.is_reachable();
     }
 
     fn get_matching_message_context(
@@ -181,8 +186,8 @@ impl MessageStore {
         ))
     }
 
-    /// this function gets the time of creation, publisher and subscriber (since there is only one)
-    /// references
+    /// this function gets the time of creation, publisher and subscriber (the only one that
+    /// received the message at QoS 2) references
     fn get_qos2_publish_context(
         &self,
         message_uuid: Uuid,
@@ -201,7 +206,11 @@ impl MessageStore {
         Some((
             publish_context.started_at,
             publish_context.sender.clone(),
-            publish_context.receivers.get(0).unwrap(),
+            // subscribers that received a downgraded copy take no part in the flow
+            publish_context
+                .receivers
+                .iter()
+                .find(|receiver| receiver.received_qos == 2)?,
         ))
     }
 
@@ -209,13 +218,43 @@ impl MessageStore {
         for msg in self.message_queue.iter_mut() {
             if let QueueMessage::Publish(publish) = msg {
                 let publish_context = self.messages.get_mut(&publish.message_uuid).unwrap();
-                if publish_context.sender.client_id == writer.client_id {
+                if !publish_context.sender.is_broker()
+                    && publish_context.sender.client_id == writer.client_id
+                {
                     publish_context.sender = writer.clone();
                 }
             }
         }
     }
 
+    /// the connection of a session ended, its messages keep a reference
+    /// without process until the client reconnects
+    pub fn take_offline(&mut self, offline: &WriterRef) {
+        for ctx in self.messages.values_mut() {
+            if ctx.sender.session_id == offline.session_id {
+                ctx.sender = offline.clone();
+            }
+            for receiver in ctx.receivers.iter_mut() {
+                if receiver.writer.session_id == offline.session_id {
+                    receiver.writer = offline.clone();
+                }
+            }
+        }
+    }
+
+    /// copies that wait for a session go to the session that resumed it
+    pub fn retarget_copies(&mut self, previous: Uuid, session_id: Uuid) {
+        let targets = self
+            .messages
+            .values_mut()
+            .filter_map(|ctx| ctx.copy_for.as_mut());
+        for session in targets.flat_map(|target| target.sessions.iter_mut()) {
+            if *session == previous {
+                *session = session_id;
+            }
+        }
+    }
+
     /// helper function to insert confirmation message to
     /// be picked up by workers eventually
     pub fn insert_confirmation_message(
@@ -342,6 +381,7 @@ impl MessageStore {
         queue_id: u128,
         sender: WriterRef,
         started_at: SystemTime,
+        expires_at: Option<SystemTime>,
     ) {
         self.message_queue
             .push(QueueMessage::Publish(PublishMessage {
@@ -358,11 +398,101 @@ impl MessageStore {
                 packet,
                 sender,
                 started_at,
+                expires_at,
                 receivers: vec![],
+                copy_for: None,
             },
         );
     }
 
+    /// queue a copy of a message that the broker publishes itself. Only the
+    /// receivers of the target get it and its QoS flow ends with them
+    pub fn insert_copy(
+        &mut self,
+        message_uuid: Uuid,
+        packet: PublishPacket,
+        queue_id: u128,
+        started_at: SystemTime,
+        expires_at: Option<SystemTime>,
+        target: CopyTarget,
+    ) {
+        self.insert_publish_message(
+            message_uuid,
+            packet,
+            queue_id,
+            WriterRef::broker(),
+            started_at,
+            expires_at,
+        );
+        if let Some(ctx) = self.messages.get_mut(&message_uuid) {
+            ctx.copy_for = Some(target);
+        }
+    }
+
+    /// remove messages whose expiry interval elapsed before they were sent
+    /// to a subscriber, this includes messages waiting for offline sessions
+    pub fn drop_expired(&mut self, now: SystemTime) -> Vec<(Uuid, PublishContext)> {
+        let messages = &self.messages;
+        let expired: Vec<Uuid> = self
+            .message_queue
+            .iter()
+            .filter_map(|msg| match msg {
+                QueueMessage::Publish(publish) if !publish.in_progress && !publish.sent => {
+                    match messages.get(&publish.message_uuid)?.expires_at {
+                        Some(expires_at) if expires_at <= now => Some(publish.message_uuid),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            })
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|uuid| {
+                MessageStore::delete_messages_by_uuid(&mut self.message_queue, uuid);
+                let ctx = self.messages.remove(&uuid)?;
+                if let Some(message_id) = ctx.packet.message_id {
+                    if self.message_ids.get(&message_id) == Some(&uuid) {
+                        self.message_ids.remove(&message_id);
+                    }
+                }
+                Some((uuid, ctx))
+            })
+            .collect()
+    }
+
+    /// remove messages that were sent to the session and still wait for its
+    /// acknowledgement, they can never be completed once the session is gone
+    pub fn drop_session_messages(&mut self, session_id: Uuid) -> Vec<(Uuid, PublishContext)> {
+        let waiting: Vec<Uuid> = self
+            .messages
+            .iter()
+            .filter(|(_, ctx)| {
+                ctx.receivers
+                    .iter()
+                    .any(|receiver| receiver.writer.session_id == session_id)
+            })
+            .map(|(uuid, _)| *uuid)
+            .collect();
+        waiting
+            .into_iter()
+            .filter_map(|uuid| self.drop_message(uuid).map(|ctx| (uuid, ctx)))
+            .collect()
+    }
+
+    /// remove a message with all its state and return its context
+    pub fn drop_message(&mut self, message_uuid: Uuid) -> Option<PublishContext> {
+        let ctx = self.messages.get(&message_uuid).cloned()?;
+        match ctx.packet.message_id {
+            Some(message_id) => self.cleanup_message(message_uuid, message_id, ctx.packet.qos),
+            None => {
+                self.drop_messages_by_uuid(message_uuid);
+                self.messages.remove(&message_uuid);
+            }
+        }
+        Some(ctx)
+    }
+
     /// create a new internal message id and map it with the given message_id
     /// from the mqtt packet if any given
     pub fn register_message_id(&mut self, message_id: Option<u16>) -> Uuid {
@@ -378,6 +508,11 @@ impl MessageStore {
         *self.message_ids.get(&message_id).unwrap()
     }
 
+    /// lookup uuid from a given message_id without failing for unknown ids
+    pub fn find_uuid(&self, message_id: u16) -> Option<Uuid> {
+        self.message_ids.get(&message_id).copied()
+    }
+
     /// get queue_id for a given message_uuid
     pub fn get_queue_id(&self, uuid: Uuid) -> Option<u128> {
         if let Some(publish) = self.get_by_uuid(uuid) {
@@ -424,35 +559,125 @@ impl MessageStore {
         true
     }
 
+    /// QoS 1 and 2 messages that have not completed their flow yet. Copies
+    /// the broker publishes itself are not persisted
+    pub fn pending(&self) -> impl Iterator<Item = (Uuid, &PublishContext)> {
+        self.messages
+            .iter()
+            .filter(|(_, context)| context.packet.qos > 0 && context.copy_for.is_none())
+            .map(|(uuid, context)| (*uuid, context))
+    }
+
+    /// remove every message that was published to the topic
+    pub fn drop_topic_messages(&mut self, topic: &str) -> Vec<(Uuid, PublishContext)> {
+        let matching: Vec<Uuid> = self
+            .messages
+            .iter()
+            .filter(|(_, ctx)| ctx.packet.topic == topic)
+            .map(|(uuid, _)| *uuid)
+            .collect();
+        matching
+            .into_iter()
+            .filter_map(|uuid| self.drop_message(uuid).map(|ctx| (uuid, ctx)))
+            .collect()
+    }
+
+    /// the QoS a stored message was published with
+    pub fn qos_of(&self, message_uuid: Uuid) -> Option<u8> {
+        self.messages.get(&message_uuid).map(|ctx| ctx.packet.qos)
+    }
+
+    /// the publisher of a stored message
+    pub fn publisher(&self, message_uuid: Uuid) -> Option<&WriterRef> {
+        self.messages.get(&message_uuid).map(|ctx| &ctx.sender)
+    }
+
+    /// a message id that no stored message uses, for messages the broker publishes itself
+    pub fn free_message_id(&self) -> Option<u16> {
+        free_message_id(&self.message_ids)
+    }
+
+    /// every stored message with the state of its QoS flow
+    pub fn inspect(&self) -> Vec<MessageInfo> {
+        self.messages
+            .iter()
+            .map(|(uuid, context)| MessageInfo {
+                uuid: *uuid,
+                topic: context.packet.topic.clone(),
+                qos: context.packet.qos,
+                message_id: context.packet.message_id,
+                publisher: context.sender.client_id.clone(),
+                state: message_state(
+                    context.packet.qos,
+                    self.get_by_uuid(*uuid),
+                    self.qos2_message_release.get(uuid),
+                ),
+            })
+            .collect()
+    }
+
     /// main logic of the message "queue" which returns the next available message
     pub fn poll(&mut self, topic_tree: &mut TopicTree) -> PollResponse {
+        let mut job = None;
         for msg in self.message_queue.iter_mut() {
             match msg {
                 QueueMessage::Publish(publish) => {
                     if !publish.in_progress {
                         let publish_context = self.messages.get(&publish.message_uuid).unwrap();
-                        if publish_context.sender.process.is_none() {
+                        if !publish_context.sender.is_reachable() {
                             continue;
                         }
                         let queue = topic_tree.get_by_id(publish.queue_id);
-                        if queue.subscribers.is_empty() {
+                        if !queue.has_subscribers() {
                             continue;
                         }
-                        publish.in_progress = true;
-                        if let None = publish_context.sender.process {
-                            return PollResponse::None;
+                        // QoS 1 and 2 messages that were sent to a session but are not yet acknowledged
+                        let messages = &self.messages;
+                        let inflight = |writer: &WriterRef| {
+                            messages
+                                .values()
+                                .filter(|ctx| {
+                                    ctx.receivers.iter().any(|r| {
+                                        r.writer.session_id == writer.session_id
+                                            && r.received_qos > 0
+                                    })
+                                })
+                                .count()
+                        };
+                        let qos = publish_context.packet.qos;
+                        let mut queue =
+                            topic_tree.select_receivers(publish.queue_id, qos, inflight);
+                        if let Some(target) = &publish_context.copy_for {
+                            queue.restrict_to(target);
                         }
-                        return PollResponse::Publish(
+                        let offline = queue.split_offline();
+                        // the message goes to the receivers that are within their Receive
+                        // Maximum, a copy stays queued for the others until they acknowledged
+                        // enough messages
+                        let mut deferred = None;
+                        if qos > 0 {
+                            let full = queue.split_by_window(inflight);
+                            if !full.is_empty() {
+                                if !queue.has_subscribers() {
+                                    continue;
+                                }
+                                match free_message_id(&self.message_ids) {
+                                    Some(message_id) => deferred = Some((message_id, full)),
+                                    None => continue,
+                                }
+                            }
+                        }
+                        publish.in_progress = true;
+                        job = Some((
                             PublishJob {
                                 message: publish.clone(),
-                                queue: queue.clone(),
+                                queue,
+                                offline,
                             },
                             publish_context.clone(),
-                        );
-                        // return PollResponse::Publish(PublishJob {
-                        //     message: publish.clone(),
-                        //     queue: queue.clone(),
-                        // });
+                            deferred,
+                        ));
+                        break;
                     }
                 }
                 QueueMessage::Confirmation(confirm) => {
@@ -466,7 +691,7 @@ impl MessageStore {
                         // mark qos1 message as waiting to prevent sending puback multiple times
                         self.waiting_qos1.insert(confirm.message_id, true);
                         let publish_context = self.messages.get(&confirm.message_uuid).unwrap();
-                        if let None = publish_context.sender.process {
+                        if !publish_context.sender.is_reachable() {
                             return PollResponse::None;
                         }
                         return PollResponse::Confirmation(
@@ -486,7 +711,7 @@ impl MessageStore {
                         // mark qos1 message as waiting to prevent sending puback multiple times
                         self.waiting_qos2.insert(complete.message_id, true);
                         let publish_context = self.messages.get(&complete.message_uuid).unwrap();
-                        if let None = publish_context.sender.process {
+                        if !publish_context.sender.is_reachable() {
                             return PollResponse::None;
                         }
                         return PollResponse::Complete(complete.clone(), publish_context.clone());
@@ -505,7 +730,7 @@ impl MessageStore {
                         // mark qos1 message as waiting to prevent sending puback multiple times
                         self.waiting_release_qos2.insert(release.message_id, true);
                         let publish_context = self.messages.get(&release.message_uuid).unwrap();
-                        if let None = publish_context.sender.process {
+                        if !publish_context.sender.is_reachable() {
                             return PollResponse::None;
                         }
                         return PollResponse::Release(release.clone(), publish_context.clone());
@@ -513,6 +738,24 @@ impl MessageStore {
                 }
             }
         }
-        PollResponse::None
+        match job {
+            Some((job, ctx, deferred)) => {
+                if let Some((message_id, target)) = deferred {
+                    let mut packet = ctx.packet.clone();
+                    packet.message_id = Some(message_id);
+                    let message_uuid = self.register_message_id(Some(message_id));
+                    self.insert_copy(
+                        message_uuid,
+                        packet,
+                        job.message.queue_id,
+                        ctx.started_at,
+                        ctx.expires_at,
+                        target,
+                    );
+                }
+                PollResponse::Publish(job, ctx)
+            }
+            None => PollResponse::None,
+        }
     }
 }
@@ -19,6 +19,7 @@ pub struct MetricsProcess {
     connected_clients: IntGauge,
     received_packets: IntCounter,
     active_queues: IntGauge,
+    expired_messages: IntCounter,
     qos0_delivery_time: Histogram,
     qos1_delivery_time: Histogram,
     qos2_delivery_time: Histogram,
@@ -43,6 +44,11 @@ impl MetricsProcess {
                 .unwrap(),
             received_packets: IntCounter::new("received_packets", "counts total packets").unwrap(),
             active_queues: IntGauge::new("active_queues", "count of active queues").unwrap(),
+            expired_messages: IntCounter::new(
+                "expired_messages",
+                "messages dropped because their expiry interval elapsed",
+            )
+            .unwrap(),
             qos0_delivery_time: Histogram::with_opts(HistogramOpts::new(
                 "qos0_delivery_time",
                 "histogram of delivery times for qos0",
@@ -69,6 +75,9 @@ impl MetricsProcess {
         res.registry
             .register(Box::new(res.active_queues.clone()))
             .unwrap();
+        res.registry
+            .register(Box::new(res.expired_messages.clone()))
+            .unwrap();
         res.registry
             .register(Box::new(res.qos0_delivery_time.clone()))
             .unwrap();
@@ -118,6 +127,11 @@ impl MetricsProcess {
         }
     }
 
+    #[handle_message]
+    pub fn track_expired_message(&mut self) {
+        self.expired_messages.inc();
+    }
+
     #[handle_message]
     pub fn track_delivery_time(&mut self, qos: u8, duration_ms: f64) {
         match qos {
@@ -135,6 +149,12 @@ impl MetricsProcess {
     // =======================
     // Request handlers
     // =======================
+    /// answers as long as the process is alive, used by the health check
+    #[handle_request]
+    pub fn ping(&self) -> bool {
+        true
+    }
+
     /// gather all metrics from the metrics process in prometheus format
     #[handle_request]
     pub fn gather(&self) -> Vec<u8> {
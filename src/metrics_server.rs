@@ -1,6 +1,19 @@
 extern crate submillisecond;
 
+use crate::ban::{BanProcess, BanProcessHandler, Peer};
+use crate::health::{self, HealthReport};
+use crate::inspect::{AdminPublish, InspectProcess, InspectProcessHandler};
 use crate::metrics::{MetricsProcess, MetricsProcessHandler};
+use crate::shutdown::{ShutdownProcess, ShutdownProcessHandler};
+use lunatic::process::{ProcessRef, StartProcess};
+use lunatic::{abstract_process, Mailbox, Process};
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use submillisecond::extract::Path;
+use submillisecond::http::header::AUTHORIZATION;
+use submillisecond::http::{HeaderMap, StatusCode};
+use submillisecond::json::Json;
+use submillisecond::response::{IntoResponse, Response};
 use submillisecond::Application;
 
 fn gather_metrics() -> Vec<u8> {
@@ -8,8 +21,316 @@ fn gather_metrics() -> Vec<u8> {
     metrics_process.gather()
 }
 
-pub fn start_server() {
-    Application::new(submillisecond::router! {
-        GET "/metrics" => gather_metrics
+/// `Authorization: Bearer <token>` has to match the configured admin token
+fn is_authorized(admin_token: Option<&str>, authorization: Option<&str>) -> bool {
+    let token = authorization.and_then(|value| value.strip_prefix("Bearer "));
+    let (expected, given) = match (admin_token, token) {
+        (Some(expected), Some(given)) => (expected.as_bytes(), given.as_bytes()),
+        _ => return false,
+    };
+    // compare in constant time so that the token cannot be guessed byte by byte
+    let difference = expected
+        .iter()
+        .zip(given.iter())
+        .fold(0, |acc, (a, b)| acc | (a ^ b));
+    difference == 0 && expected.len() == given.len()
+}
+
+/// Holds the admin token of the config that was loaded on startup. The
+/// handlers run in processes of their own and ask it on every request
+pub struct AdminProcess {
+    admin_token: Option<String>,
+}
+
+#[abstract_process(visibility = pub)]
+impl AdminProcess {
+    #[init]
+    fn init(_: ProcessRef<Self>, admin_token: Option<String>) -> Self {
+        AdminProcess { admin_token }
+    }
+
+    #[handle_request]
+    fn authorize(&self, authorization: Option<String>) -> bool {
+        is_authorized(self.admin_token.as_deref(), authorization.as_deref())
+    }
+}
+
+fn with_status(response: impl IntoResponse, status: StatusCode) -> Response {
+    let mut response = response.into_response();
+    *response.status_mut() = status;
+    response
+}
+
+/// run an admin action if the request carries the admin token, 401 otherwise
+fn admin<T: IntoResponse>(headers: &HeaderMap, action: impl FnOnce() -> T) -> Response {
+    let authorization = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok());
+    // without the admin process nobody is authorized
+    let authorized = ProcessRef::<AdminProcess>::lookup("admin")
+        .is_some_and(|admin| admin.authorize(authorization.map(str::to_owned)));
+    if !authorized {
+        lunatic_log::info!("[Admin] Refused a request without a valid admin token");
+        return with_status(Json("invalid admin token"), StatusCode::UNAUTHORIZED);
+    }
+    action().into_response()
+}
+
+fn list_bans(headers: HeaderMap) -> Response {
+    admin(&headers, || Json(BanProcess::get_process().list_bans()))
+}
+
+/// lift all bans, responds with the number of lifted bans
+fn clear_bans(headers: HeaderMap) -> Response {
+    admin(&headers, || {
+        Json(BanProcess::get_process().clear_bans(None))
+    })
+}
+
+fn clear_ip_ban(headers: HeaderMap, Path(ip): Path<String>) -> Response {
+    admin(&headers, || match ip.parse::<IpAddr>() {
+        Ok(ip) => Json(BanProcess::get_process().clear_bans(Some(Peer::Ip(ip)))),
+        Err(_) => Json(0),
+    })
+}
+
+fn clear_client_ban(headers: HeaderMap, Path(client_id): Path<String>) -> Response {
+    admin(&headers, || {
+        Json(BanProcess::get_process().clear_bans(Some(Peer::ClientId(client_id))))
+    })
+}
+
+/// the JSON report with 200 if the broker is up and 503 otherwise
+fn health_response(report: HealthReport) -> Response {
+    let status = if report.is_up() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    with_status(Json(report), status)
+}
+
+fn healthz() -> Response {
+    health_response(health::liveness())
+}
+
+fn readyz() -> Response {
+    health_response(health::readiness())
+}
+
+fn inspect_clients(headers: HeaderMap) -> Response {
+    admin(&headers, || Json(InspectProcess::get_process().clients()))
+}
+
+fn inspect_topics(headers: HeaderMap) -> Response {
+    admin(&headers, || Json(InspectProcess::get_process().topics()))
+}
+
+fn inspect_messages(headers: HeaderMap) -> Response {
+    admin(&headers, || Json(InspectProcess::get_process().messages()))
+}
+
+fn inspect_wal(headers: HeaderMap) -> Response {
+    admin(&headers, || Json(InspectProcess::get_process().wal()))
+}
+
+/// body of the admin actions on a topic
+#[derive(Debug, Serialize, Deserialize)]
+struct TopicRequest {
+    topic: String,
+}
+
+/// disconnect a client, responds with false if it is not connected
+fn kick_client(headers: HeaderMap, Path(client_id): Path<String>) -> Response {
+    admin(&headers, || {
+        Json(InspectProcess::get_process().kick_client(client_id))
+    })
+}
+
+/// drop a session with its subscriptions, responds with false if there is none
+fn drop_session(headers: HeaderMap, Path(client_id): Path<String>) -> Response {
+    admin(&headers, || {
+        Json(InspectProcess::get_process().drop_session(client_id))
+    })
+}
+
+/// drop the pending messages of a topic, responds with their number
+fn purge_topic(headers: HeaderMap, Json(request): Json<TopicRequest>) -> Response {
+    admin(&headers, || {
+        Json(InspectProcess::get_process().purge_topic(request.topic))
+    })
+}
+
+fn delete_retained(headers: HeaderMap, Json(request): Json<TopicRequest>) -> Response {
+    admin(&headers, || {
+        Json(InspectProcess::get_process().delete_retained(request.topic))
+    })
+}
+
+/// publish a test message, an invalid topic or QoS is answered with 400
+fn publish(headers: HeaderMap, Json(message): Json<AdminPublish>) -> Response {
+    admin(&headers, || {
+        match InspectProcess::get_process().publish(message) {
+            Ok(queued) => Json(queued).into_response(),
+            Err(e) => with_status(Json(e), StatusCode::BAD_REQUEST),
+        }
+    })
+}
+
+/// start a graceful shutdown, responds with false if it is already running
+fn shutdown(headers: HeaderMap) -> Response {
+    admin(&headers, || Json(ShutdownProcess::get_process().begin()))
+}
+
+/// serve the metrics and admin endpoints in a separate process
+pub fn start_server(port: u16, admin_token: Option<String>) {
+    AdminProcess::start_link(admin_token, Some("admin"));
+    Process::spawn_link(port, |port, _: Mailbox<()>| {
+        let address = format!("0.0.0.0:{}", port);
+        lunatic_log::info!("Started http server on port {}", port);
+        Application::new(submillisecond::router! {
+            GET "/metrics" => gather_metrics
+            GET "/healthz" => healthz
+            GET "/readyz" => readyz
+            GET "/inspect/clients" => inspect_clients
+            GET "/inspect/topics" => inspect_topics
+            GET "/inspect/messages" => inspect_messages
+            GET "/inspect/wal" => inspect_wal
+            DELETE "/admin/clients/:client_id" => kick_client
+            DELETE "/admin/sessions/:client_id" => drop_session
+            POST "/admin/purge" => purge_topic
+            DELETE "/admin/retained" => delete_retained
+            POST "/admin/publish" => publish
+            GET "/bans" => list_bans
+            DELETE "/bans" => clear_bans
+            DELETE "/bans/ip/:ip" => clear_ip_ban
+            DELETE "/bans/client/:client_id" => clear_client_ban
+            POST "/shutdown" => shutdown
+        })
+        .serve(address)
+        .unwrap();
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ban::BanSup;
+    use crate::config::BrokerConfig;
+    use crate::coordinator::{CoordinatorProcess, CoordinatorSup};
+    use crate::inspect::InspectSup;
+    use crate::metrics::MetricsSup;
+
+    #[test]
+    fn admin_token() {
+        assert!(is_authorized(Some("secret"), Some("Bearer secret")));
+        assert!(!is_authorized(Some("secret"), Some("Bearer secre")));
+        assert!(!is_authorized(Some("secret"), Some("Bearer secret2")));
+        assert!(!is_authorized(Some("secret"), Some("secret")));
+        assert!(!is_authorized(Some("secret"), None));
+        // without a configured token the admin endpoints are disabled
+        assert!(!is_authorized(None, Some("Bearer ")));
+    }
+
+    fn with_token(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, format!("Bearer {}", token).parse().unwrap());
+        headers
+    }
+
+    const TOKEN: &str = "secret";
+
+    /// the processes behind the admin routes, with `TOKEN` as admin token
+    fn start_admin_api() {
+        if ProcessRef::<AdminProcess>::lookup("admin").is_some() {
+            return;
+        }
+        // the coordinator tests may have started the broker already
+        if ProcessRef::<CoordinatorProcess>::lookup("coordinator").is_none() {
+            MetricsSup::start("metrics".to_owned(), None);
+            BanSup::start(("bans".to_owned(), Default::default()), None);
+            CoordinatorSup::start(("coordinator".to_owned(), BrokerConfig::default()), None);
+        }
+        InspectSup::start("inspect".to_owned(), None);
+        AdminProcess::start(Some(TOKEN.to_owned()), Some("admin"));
+    }
+
+    /// the route refuses a missing or wrong token and runs with the right one
+    fn assert_guarded(route: impl Fn(HeaderMap) -> Response) {
+        assert_eq!(route(HeaderMap::new()).status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            route(with_token("guessed")).status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(route(with_token(TOKEN)).status(), StatusCode::OK);
+    }
+
+    #[lunatic::test]
+    fn admin_routes_with_a_configured_token() {
+        start_admin_api();
+        assert_guarded(|headers| kick_client(headers, Path("sensor-1".to_owned())));
+        assert_guarded(|headers| drop_session(headers, Path("sensor-1".to_owned())));
+        let topic = || TopicRequest {
+            topic: "sensors/1".to_owned(),
+        };
+        assert_guarded(|headers| purge_topic(headers, Json(topic())));
+        assert_guarded(|headers| delete_retained(headers, Json(topic())));
+        assert_guarded(|headers| {
+            let message = AdminPublish {
+                topic: "sensors/1".to_owned(),
+                payload: "21".to_owned(),
+                qos: 0,
+                retain: false,
+            };
+            publish(headers, Json(message))
+        });
+    }
+
+    #[test]
+    fn ban_routes_require_the_admin_token() {
+        for headers in [HeaderMap::new(), with_token("guessed")] {
+            let responses = [
+                list_bans(headers.clone()),
+                clear_bans(headers.clone()),
+                clear_ip_ban(headers.clone(), Path("10.0.0.1".to_owned())),
+                clear_client_ban(headers, Path("sensor-1".to_owned())),
+            ];
+            for response in responses {
+                assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            }
+        }
+    }
+
+    #[test]
+    fn inspect_routes_require_the_admin_token() {
+        for headers in [HeaderMap::new(), with_token("guessed")] {
+            let responses = [
+                inspect_clients(headers.clone()),
+                inspect_topics(headers.clone()),
+                inspect_messages(headers.clone()),
+                inspect_wal(headers),
+            ];
+            for response in responses {
+                assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            }
+        }
+    }
+
+    #[lunatic::test]
+    fn inspect_routes_with_a_configured_token() {
+        start_admin_api();
+        assert_guarded(inspect_clients);
+        assert_guarded(inspect_topics);
+        assert_guarded(inspect_messages);
+        assert_guarded(inspect_wal);
+    }
+
+    #[lunatic::test]
+    fn ban_routes_with_a_configured_token() {
+        start_admin_api();
+        assert_guarded(list_bans);
+        assert_guarded(clear_bans);
+        assert_guarded(|headers| clear_ip_ban(headers, Path("10.0.0.1".to_owned())));
+        assert_guarded(|headers| clear_client_ban(headers, Path("sensor-1".to_owned())));
+    }
+}
@@ -1,9 +1,10 @@
-use crate::structure::WriterRef;
+use crate::structure::{PublishContext, WriterRef};
 use base64;
 use mqtt_packet_3_5::{ConfirmationPacket, PublishPacket};
 use ron;
 use serde::{Deserialize, Serialize};
-use std::fs::{DirBuilder, File};
+use std::collections::BTreeMap;
+use std::fs::{self, DirBuilder, File, OpenOptions};
 use std::io::{BufRead, BufReader, Result as IoResult, Write};
 use std::path::{Path, PathBuf};
 use std::str;
@@ -16,6 +17,18 @@ pub struct FileLog {
     // file_name: str,
     full_path: PathBuf,
     file: File,
+    /// number of entries appended since the broker started by their type
+    appended: BTreeMap<String, u64>,
+    compactions: u64,
+}
+
+/// Size and activity of the log for the admin API
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WalStats {
+    pub path: String,
+    pub size_bytes: u64,
+    pub appended: BTreeMap<String, u64>,
+    pub compactions: u64,
 }
 
 const NEWLINE: &[u8] = &[b'\n'];
@@ -28,6 +41,19 @@ const ACCEPTED: u8 = 2;
 const SENT: u8 = 3;
 const DELETED: u8 = 4;
 const COMPLETE: u8 = 5;
+const SESSION_PURGED: u8 = 6;
+
+fn entry_name(header: u8) -> &'static str {
+    match header {
+        PUBLISH => "publish",
+        ACCEPTED => "accepted",
+        SENT => "sent",
+        DELETED => "deleted",
+        COMPLETE => "complete",
+        SESSION_PURGED => "session_purged",
+        _ => "unknown",
+    }
+}
 
 // structures that will be stored per entry
 /// PublishEntry is the structure used to write a log entry
@@ -80,6 +106,15 @@ pub struct CompleteEntry {
     pub completed_at: SystemTime,
 }
 
+/// SessionPurgedEntry is written once a session was removed together
+/// with its subscriptions and the messages waiting for it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SessionPurgedEntry {
+    pub client_id: String,
+    pub session: Uuid,
+    pub purged_at: SystemTime,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Entry {
     Publish(PublishEntry),
@@ -87,6 +122,7 @@ pub enum Entry {
     Sent(SentEntry),
     Deleted(DeletedEntry),
     Completed(CompleteEntry),
+    SessionPurged(SessionPurgedEntry),
 }
 
 /// Each line will start with 1 byte that indicates type of entry followed by a
@@ -104,6 +140,8 @@ impl FileLog {
                 // write 0 as initial cursor
                 Ok(file) => file,
             },
+            appended: BTreeMap::new(),
+            compactions: 0,
         }
     }
 
@@ -179,6 +217,69 @@ impl FileLog {
         );
     }
 
+    pub fn append_session_purge(&mut self, writer: &WriterRef, purged_at: SystemTime) {
+        self.append(
+            SESSION_PURGED,
+            ron::to_string(&SessionPurgedEntry {
+                client_id: writer.client_id.clone(),
+                session: writer.session_id,
+                purged_at,
+            })
+            .unwrap()
+            .as_bytes(),
+        );
+    }
+
+    /// write buffered entries to the file and wait until they reached the disk
+    pub fn sync(&mut self) -> IoResult<()> {
+        self.file.flush()?;
+        self.file.sync_all()
+    }
+
+    /// replace the log with a snapshot that only holds the publish entries of
+    /// the pending messages and whether they were sent, which is all that is
+    /// needed for a recovery. The snapshot is written next to the log and
+    /// renamed once it is on disk
+    pub fn compact<'a>(
+        &mut self,
+        pending: impl Iterator<Item = (Uuid, &'a PublishContext)>,
+    ) -> IoResult<()> {
+        let snapshot_path = self.full_path.with_extension("compact");
+        let mut snapshot = FileLog {
+            file: File::create(&snapshot_path)?,
+            full_path: snapshot_path.clone(),
+            appended: BTreeMap::new(),
+            compactions: 0,
+        };
+        let now = SystemTime::now();
+        for (uuid, context) in pending {
+            snapshot.append_publish(
+                uuid,
+                context.packet.clone(),
+                &context.sender,
+                context.started_at,
+            );
+            // a QoS 2 message that reached a subscriber must not be sent again
+            if !context.receivers.is_empty() {
+                snapshot.append_sent(uuid, now);
+            }
+        }
+        snapshot.sync()?;
+        fs::rename(&snapshot_path, &self.full_path)?;
+        self.file = OpenOptions::new().append(true).open(&self.full_path)?;
+        self.compactions += 1;
+        Ok(())
+    }
+
+    pub fn stats(&self) -> WalStats {
+        WalStats {
+            path: self.full_path.display().to_string(),
+            size_bytes: self.file.metadata().map_or(0, |metadata| metadata.len()),
+            appended: self.appended.clone(),
+            compactions: self.compactions,
+        }
+    }
+
     pub fn append(&mut self, header: u8, data: &[u8]) {
         // let x: MyStruct = ron::from_str("(boolean: true, float: 1.23)").unwrap();
         let encoded = base64::encode(data);
@@ -193,6 +294,10 @@ impl FileLog {
                 self.full_path
             ),
         };
+        *self
+            .appended
+            .entry(entry_name(header).to_owned())
+            .or_default() += 1;
     }
 
     pub fn read_file(cwd: &str, file_name: &str) -> IoResult<Vec<Entry>> {
@@ -229,6 +334,11 @@ impl FileLog {
                                 ron::from_str::<CompleteEntry>(decoded).unwrap(),
                             ));
                         }
+                        SESSION_PURGED => {
+                            res.push(Entry::SessionPurged(
+                                ron::from_str::<SessionPurgedEntry>(decoded).unwrap(),
+                            ));
+                        }
                         other => panic!(
                             "[persistence] Corrupted file entry. Begins with {:?}",
                             other
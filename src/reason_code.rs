@@ -0,0 +1,77 @@
+//! MQTT v5 reason codes that the broker sends in DISCONNECT and CONNACK packets
+//! and the CONNACK return codes of v3.1.1
+
+// =======================
+// v3.1.1 return codes
+// =======================
+/// The Server does not support the level of the MQTT protocol requested by the Client
+pub const UNACCEPTABLE_PROTOCOL_VERSION: u8 = 0x01;
+/// The Client identifier is correct UTF-8 but not allowed by the Server
+pub const IDENTIFIER_REJECTED: u8 = 0x02;
+/// The Client is not authorized to connect
+pub const NOT_AUTHORIZED: u8 = 0x05;
+
+// =======================
+// v5 reason codes
+// =======================
+
+/// Continue the authentication with another step
+pub const CONTINUE_AUTHENTICATION: u8 = 0x18;
+/// Initiate a re-authentication
+pub const REAUTHENTICATE: u8 = 0x19;
+/// The Server does not wish to reveal the reason for the failure
+pub const UNSPECIFIED_ERROR: u8 = 0x80;
+/// Data within the packet could not be correctly parsed
+pub const MALFORMED_PACKET: u8 = 0x81;
+/// Data in the packet does not conform to this specification
+pub const PROTOCOL_ERROR: u8 = 0x82;
+/// The Server does not support the version of the MQTT protocol requested by the Client
+pub const UNSUPPORTED_PROTOCOL_VERSION: u8 = 0x84;
+/// The Client Identifier is a valid string but is not allowed by the Server
+pub const CLIENT_IDENTIFIER_NOT_VALID: u8 = 0x85;
+/// The Client is not authorized to connect or to do this
+pub const NOT_AUTHORIZED_V5: u8 = 0x87;
+/// The Client has been banned by administrative action
+pub const BANNED: u8 = 0x8A;
+/// The authentication method is not supported or does not match the one in use
+pub const BAD_AUTHENTICATION_METHOD: u8 = 0x8C;
+/// The Server is shutting down
+pub const SERVER_SHUTTING_DOWN: u8 = 0x8B;
+/// The Connection is closed because no packet has been received for 1.5 times the Keepalive time
+pub const KEEP_ALIVE_TIMEOUT: u8 = 0x8D;
+/// Another connection using the same client id has connected
+pub const SESSION_TAKEN_OVER: u8 = 0x8E;
+/// The Topic Name is correctly formed, but is not accepted
+pub const TOPIC_NAME_INVALID: u8 = 0x90;
+/// The client sent more QoS 1 and 2 publishes than the Receive Maximum allows
+pub const RECEIVE_MAXIMUM_EXCEEDED: u8 = 0x93;
+/// The Topic Alias is 0, greater than the maximum or not set
+pub const TOPIC_ALIAS_INVALID: u8 = 0x94;
+/// The packet size is greater than the maximum packet size
+pub const PACKET_TOO_LARGE: u8 = 0x95;
+/// The Connection is closed by an administrator
+pub const ADMINISTRATIVE_ACTION: u8 = 0x98;
+/// The payload format does not match the one specified by the Payload Format Indicator
+pub const PAYLOAD_FORMAT_INVALID: u8 = 0x99;
+
+/// human readable reason string that is sent along with a DISCONNECT
+pub fn description(reason_code: u8) -> Option<&'static str> {
+    let description = match reason_code {
+        UNSPECIFIED_ERROR => "unspecified error",
+        MALFORMED_PACKET => "malformed packet",
+        PROTOCOL_ERROR => "protocol error",
+        NOT_AUTHORIZED_V5 => "not authorized",
+        SERVER_SHUTTING_DOWN => "server shutting down",
+        BAD_AUTHENTICATION_METHOD => "bad authentication method",
+        KEEP_ALIVE_TIMEOUT => "keep alive timeout",
+        SESSION_TAKEN_OVER => "session taken over by another connection",
+        TOPIC_NAME_INVALID => "topic name invalid",
+        RECEIVE_MAXIMUM_EXCEEDED => "receive maximum exceeded",
+        TOPIC_ALIAS_INVALID => "topic alias invalid",
+        PACKET_TOO_LARGE => "packet too large",
+        ADMINISTRATIVE_ACTION => "disconnected by an administrator",
+        PAYLOAD_FORMAT_INVALID => "payload format invalid",
+        _ => return None,
+    };
+    Some(description)
+}
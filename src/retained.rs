@@ -0,0 +1,93 @@
+use crate::topic_tree::State;
+use mqtt_packet_3_5::PublishPacket;
+use std::collections::HashMap;
+use std::time::SystemTime;
+
+#[derive(Debug, Clone)]
+pub struct RetainedMessage {
+    pub packet: PublishPacket,
+    pub expires_at: Option<SystemTime>,
+}
+
+/// The last retained message of every topic. New subscribers receive the
+/// messages of all topics that match their filter
+#[derive(Debug, Default)]
+pub struct RetainedStore {
+    messages: HashMap<String, RetainedMessage>,
+}
+
+impl RetainedStore {
+    /// keep the message for future subscribers, an empty payload removes
+    /// the retained message of the topic
+    pub fn store(&mut self, packet: &PublishPacket, expires_at: Option<SystemTime>) {
+        if packet.payload.is_empty() {
+            self.messages.remove(&packet.topic);
+            return;
+        }
+        self.messages.insert(
+            packet.topic.clone(),
+            RetainedMessage {
+                packet: packet.clone(),
+                expires_at,
+            },
+        );
+    }
+
+    /// drop the retained message of the topic, returns false if there is none
+    pub fn remove(&mut self, topic: &str) -> bool {
+        self.messages.remove(topic).is_some()
+    }
+
+    /// all retained messages that match the filter and did not expire yet
+    pub fn matching(&mut self, filter: &str, now: SystemTime) -> Vec<RetainedMessage> {
+        self.messages
+            .retain(|_, message| !matches!(message.expires_at, Some(at) if at <= now));
+        self.messages
+            .iter()
+            .filter(|(topic, _)| match filter.contains(&['+', '#'][..]) {
+                true => State::match_topic(filter, topic),
+                false => filter == topic.as_str(),
+            })
+            .map(|(_, message)| message.clone())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn publish(topic: &str, payload: &[u8]) -> PublishPacket {
+        PublishPacket {
+            dup: false,
+            qos: 1,
+            retain: true,
+            topic: topic.to_owned(),
+            message_id: Some(1),
+            payload: payload.to_vec(),
+            properties: None,
+        }
+    }
+
+    #[test]
+    fn retained_messages() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        let mut store = RetainedStore::default();
+        store.store(&publish("sensors/1", b"20"), None);
+        store.store(&publish("sensors/1", b"21"), None);
+        store.store(&publish("sensors/2", b"19"), Some(now));
+        store.store(&publish("sensors/3", b"18"), None);
+        store.store(&publish("sensors/3", b""), None);
+
+        let matching = store.matching("sensors/+", now);
+        assert_eq!(matching.len(), 1);
+        assert_eq!(matching[0].packet.payload, b"21");
+        assert_eq!(store.matching("sensors/1", now).len(), 1);
+        assert!(store.matching("sensors", now).is_empty());
+
+        assert!(store.remove("sensors/1"));
+        assert!(!store.remove("sensors/1"));
+        assert!(store.matching("sensors/+", now).is_empty());
+    }
+}
@@ -0,0 +1,297 @@
+//! Server side of SCRAM-SHA-256 (RFC 5802 and RFC 7677). The password never
+//! leaves the client, the broker only stores keys derived from it
+use crate::auth::{AuthStep, Authenticator};
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use uuid::Uuid;
+
+pub const METHOD: &str = "SCRAM-SHA-256";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The keys of one user as they are stored in the credentials file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScramCredential {
+    /// base64 encoded salt
+    pub salt: String,
+    pub iterations: u32,
+    /// base64 encoded `H(ClientKey)`
+    pub stored_key: String,
+    /// base64 encoded `HMAC(SaltedPassword, "Server Key")`
+    pub server_key: String,
+}
+
+impl ScramCredential {
+    /// derive the stored keys of a password, used to fill the credentials file
+    pub fn new(password: &str, salt: &[u8], iterations: u32) -> ScramCredential {
+        let salted_password = hi(password.as_bytes(), salt, iterations);
+        let client_key = hmac(&salted_password, b"Client Key");
+        ScramCredential {
+            salt: base64::encode(salt),
+            iterations,
+            stored_key: base64::encode(Sha256::digest(client_key)),
+            server_key: base64::encode(hmac(&salted_password, b"Server Key")),
+        }
+    }
+}
+
+/// read the credentials file, a RON map from username to `ScramCredential`
+pub fn load_credentials(file: &str) -> HashMap<String, ScramCredential> {
+    let contents = match fs::read_to_string(file) {
+        Ok(contents) => contents,
+        Err(e) => {
+            lunatic_log::error!("[Auth] Failed to read {} | {}", file, e);
+            return HashMap::new();
+        }
+    };
+    ron::from_str(&contents).unwrap_or_else(|e| {
+        lunatic_log::error!("[Auth] Failed to parse {} | {}", file, e);
+        HashMap::new()
+    })
+}
+
+enum State {
+    /// waiting for the client-first-message
+    Initial,
+    /// the server-first-message was sent, waiting for the client-final-message
+    Challenged {
+        username: String,
+        gs2_header: String,
+        nonce: String,
+        auth_message: String,
+        credential: ScramCredential,
+    },
+    Done,
+}
+
+pub struct ScramSha256 {
+    credentials: HashMap<String, ScramCredential>,
+    server_nonce: String,
+    state: State,
+}
+
+impl ScramSha256 {
+    pub fn new(credentials: HashMap<String, ScramCredential>) -> ScramSha256 {
+        let server_nonce = format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple());
+        ScramSha256::with_nonce(credentials, server_nonce)
+    }
+
+    pub fn with_nonce(
+        credentials: HashMap<String, ScramCredential>,
+        server_nonce: String,
+    ) -> ScramSha256 {
+        ScramSha256 {
+            credentials,
+            server_nonce,
+            state: State::Initial,
+        }
+    }
+
+    fn client_first(&mut self, message: &str) -> Result<Vec<u8>, String> {
+        // gs2-header: channel binding flag and an optional authzid
+        let mut parts = message.splitn(3, ',');
+        let (binding, authzid, bare) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(binding), Some(authzid), Some(bare)) => (binding, authzid, bare),
+            _ => return Err("invalid client-first-message".to_owned()),
+        };
+        if binding != "n" && binding != "y" {
+            return Err("channel binding is not supported".to_owned());
+        }
+        let attributes = attributes(bare);
+        let username = match attributes.get(&'n') {
+            Some(name) => name.replace("=2C", ",").replace("=3D", "="),
+            None => return Err("missing username".to_owned()),
+        };
+        if !authzid.is_empty() && authzid != format!("a={}", username) {
+            return Err("authorization identity differs from the username".to_owned());
+        }
+        let client_nonce = match attributes.get(&'r') {
+            Some(nonce) if !nonce.is_empty() => nonce,
+            _ => return Err("missing nonce".to_owned()),
+        };
+        let credential = match self.credentials.get(&username) {
+            Some(credential) => credential.clone(),
+            None => return Err(format!("unknown user {}", username)),
+        };
+        let nonce = format!("{}{}", client_nonce, self.server_nonce);
+        let server_first = format!(
+            "r={},s={},i={}",
+            nonce, credential.salt, credential.iterations
+        );
+        self.state = State::Challenged {
+            username,
+            gs2_header: format!("{},{},", binding, authzid),
+            nonce,
+            auth_message: format!("{},{}", bare, server_first),
+            credential,
+        };
+        Ok(server_first.into_bytes())
+    }
+
+    fn client_final(&mut self, message: &str) -> Result<(String, Vec<u8>), String> {
+        let (username, gs2_header, nonce, auth_message, credential) =
+            match std::mem::replace(&mut self.state, State::Done) {
+                State::Challenged {
+                    username,
+                    gs2_header,
+                    nonce,
+                    auth_message,
+                    credential,
+                } => (username, gs2_header, nonce, auth_message, credential),
+                _ => return Err("unexpected client-final-message".to_owned()),
+            };
+        let (without_proof, proof) = match message.rsplit_once(",p=") {
+            Some(split) => split,
+            None => return Err("missing proof".to_owned()),
+        };
+        let attributes = attributes(without_proof);
+        if attributes.get(&'c') != Some(&base64::encode(gs2_header)) {
+            return Err("channel binding does not match".to_owned());
+        }
+        if attributes.get(&'r') != Some(&nonce) {
+            return Err("nonce does not match".to_owned());
+        }
+        let decode = |value: &str| base64::decode(value).map_err(|e| e.to_string());
+        let proof = decode(proof)?;
+        let stored_key = decode(&credential.stored_key)?;
+        let server_key = decode(&credential.server_key)?;
+
+        let auth_message = format!("{},{}", auth_message, without_proof);
+        let client_signature = hmac(&stored_key, auth_message.as_bytes());
+        if proof.len() != client_signature.len() {
+            return Err("invalid proof".to_owned());
+        }
+        let client_key: Vec<u8> = proof
+            .iter()
+            .zip(client_signature.iter())
+            .map(|(a, b)| a ^ b)
+            .collect();
+        // compare in constant time so that the stored key cannot be guessed byte by byte
+        let difference = Sha256::digest(&client_key)
+            .iter()
+            .zip(stored_key.iter())
+            .fold(0, |acc, (a, b)| acc | (a ^ b));
+        if difference != 0 || stored_key.len() != 32 {
+            return Err(format!("wrong password for {}", username));
+        }
+        let server_signature = hmac(&server_key, auth_message.as_bytes());
+        let server_final = format!("v={}", base64::encode(server_signature));
+        Ok((username, server_final.into_bytes()))
+    }
+}
+
+impl Authenticator for ScramSha256 {
+    fn step(&mut self, data: &[u8]) -> AuthStep {
+        let message = match std::str::from_utf8(data) {
+            Ok(message) => message,
+            Err(_) => return AuthStep::Failure("message is not valid UTF-8".to_owned()),
+        };
+        let result = match self.state {
+            State::Initial => self.client_first(message).map(AuthStep::Continue),
+            State::Challenged { .. } => {
+                self.client_final(message)
+                    .map(|(username, data)| AuthStep::Success {
+                        username,
+                        data: Some(data),
+                    })
+            }
+            State::Done => Err("authentication is already finished".to_owned()),
+        };
+        result.unwrap_or_else(AuthStep::Failure)
+    }
+}
+
+/// the `key=value` attributes of a SCRAM message
+fn attributes(message: &str) -> HashMap<char, String> {
+    message
+        .split(',')
+        .filter_map(|attribute| {
+            let mut chars = attribute.chars();
+            let key = chars.next()?;
+            let value = chars.as_str().strip_prefix('=')?;
+            Some((key, value.to_owned()))
+        })
+        .collect()
+}
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    // HMAC accepts keys of any length
+    let mut mac = HmacSha256::new_from_slice(key).unwrap();
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// PBKDF2 with HMAC-SHA-256 and a single output block
+fn hi(password: &[u8], salt: &[u8], iterations: u32) -> Vec<u8> {
+    let mut block = hmac(password, &[salt, &1u32.to_be_bytes()].concat());
+    let mut result = block.clone();
+    for _ in 1..iterations {
+        block = hmac(password, &block);
+        for (r, b) in result.iter_mut().zip(block.iter()) {
+            *r ^= b;
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn authenticator() -> ScramSha256 {
+        let salt = base64::decode("W22ZaJ0SNY7soEsUEjb6gQ==").unwrap();
+        let credentials = HashMap::from([(
+            "user".to_owned(),
+            ScramCredential::new("pencil", &salt, 4096),
+        )]);
+        ScramSha256::with_nonce(credentials, "%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0".to_owned())
+    }
+
+    // the example exchange of RFC 7677
+    #[test]
+    fn rfc_7677_exchange() {
+        let mut scram = authenticator();
+        let server_first = scram.step(b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO");
+        assert_eq!(
+            server_first,
+            AuthStep::Continue(
+                b"r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,s=W22ZaJ0SNY7soEsUEjb6gQ==,i=4096"
+                    .to_vec()
+            )
+        );
+        let server_final = scram.step(
+            b"c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=dHzbZapWIk4jUhN+Ute9ytag9zjfMHgsqmmiz7AndVQ=",
+        );
+        assert_eq!(
+            server_final,
+            AuthStep::Success {
+                username: "user".to_owned(),
+                data: Some(b"v=6rriTRBi23WpRR/wtup+mMhUZUn/dB5nLTJRsjl95G4=".to_vec()),
+            }
+        );
+        assert!(matches!(scram.step(b"n,,n=user,r=x"), AuthStep::Failure(_)));
+    }
+
+    #[test]
+    fn wrong_password() {
+        let mut scram = authenticator();
+        scram.step(b"n,,n=user,r=rOprNGfwEbeRWgbNEkqO");
+        let step = scram.step(
+            b"c=biws,r=rOprNGfwEbeRWgbNEkqO%hvYDpWUa2RaTCAfuxFIlj)hNlF$k0,p=AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=",
+        );
+        assert!(matches!(step, AuthStep::Failure(_)));
+
+        let mut scram = authenticator();
+        assert!(matches!(
+            scram.step(b"n,,n=nobody,r=rOprNGfwEbeRWgbNEkqO"),
+            AuthStep::Failure(_)
+        ));
+        assert!(matches!(
+            scram.step(b"p=tls-unique,,n=user,r=abc"),
+            AuthStep::Failure(_)
+        ));
+    }
+}
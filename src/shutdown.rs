@@ -0,0 +1,144 @@
+//! Graceful shutdown of the broker. New connections are refused, the worker
+//! finishes its current job, every client is disconnected and the WAL is
+//! flushed and compacted before the main process returns. The lunatic runtime
+//! does not forward SIGTERM and SIGINT, `run_server.sh` traps them and creates
+//! the `SHUTDOWN_REQUEST` file instead, which starts the shutdown without the
+//! admin token
+use crate::coordinator::{CoordinatorProcess, CoordinatorProcessHandler};
+use lunatic::process::ProcessRef;
+use lunatic::supervisor::Supervisor;
+use lunatic::{abstract_process, sleep, Mailbox, Process};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
+
+/// how often the progress of the shutdown is checked
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// file that requests a graceful shutdown when it appears
+pub const SHUTDOWN_REQUEST: &str = "persistence/shutdown.request";
+
+pub struct ShutdownSup;
+impl Supervisor for ShutdownSup {
+    type Arg = (String, u64);
+    type Children = ShutdownProcess;
+
+    fn init(
+        config: &mut lunatic::supervisor::SupervisorConfig<Self>,
+        (name, timeout_secs): Self::Arg,
+    ) {
+        config.children_args((timeout_secs, Some(name)))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum ShutdownState {
+    Running,
+    ShuttingDown,
+    Finished,
+}
+
+pub struct ShutdownProcess {
+    state: ShutdownState,
+    timeout: Duration,
+}
+
+#[abstract_process(visibility = pub)]
+impl ShutdownProcess {
+    /// function that retrieves the running shutdown process
+    pub fn get_process() -> ProcessRef<ShutdownProcess> {
+        ProcessRef::<ShutdownProcess>::lookup("shutdown").unwrap()
+    }
+
+    #[init]
+    fn init(_: ProcessRef<Self>, timeout_secs: u64) -> Self {
+        ShutdownProcess {
+            state: ShutdownState::Running,
+            timeout: Duration::from_secs(timeout_secs),
+        }
+    }
+
+    /// start the shutdown, returns false if it is already running
+    #[handle_request]
+    fn begin(&mut self) -> bool {
+        if self.state != ShutdownState::Running {
+            return false;
+        }
+        lunatic_log::info!("[Shutdown] Shutting down within {:?}", self.timeout);
+        self.state = ShutdownState::ShuttingDown;
+        // not linked, the deadline of the main process still applies if it fails
+        Process::spawn(self.timeout, |timeout, _: Mailbox<()>| {
+            flush_and_disconnect(timeout);
+            ShutdownProcess::get_process().finish();
+        });
+        true
+    }
+
+    #[handle_message]
+    fn finish(&mut self) {
+        lunatic_log::info!("[Shutdown] Finished");
+        self.state = ShutdownState::Finished;
+    }
+
+    #[handle_request]
+    fn status(&self) -> ShutdownState {
+        self.state
+    }
+
+    /// listeners refuse new connections once the shutdown started
+    #[handle_request]
+    fn is_accepting(&self) -> bool {
+        self.state == ShutdownState::Running
+    }
+}
+
+/// the worker gets half of the timeout to finish its job, the rest is left
+/// for disconnecting the clients and writing the WAL. The WAL comes last so
+/// that the snapshot includes the sessions that ended with the disconnect
+fn flush_and_disconnect(timeout: Duration) {
+    let coordinator = ProcessRef::<CoordinatorProcess>::lookup("coordinator").unwrap();
+    let deadline = SystemTime::now() + timeout / 2;
+    while !coordinator.drain() {
+        if SystemTime::now() > deadline {
+            lunatic_log::error!("[Shutdown] The worker did not finish its job in time");
+            break;
+        }
+        sleep(POLL_INTERVAL);
+    }
+    coordinator.disconnect_all();
+    coordinator.flush_state();
+}
+
+/// block until the broker was shut down or the shutdown exceeded its
+/// timeout. The broker exits once the main process returns
+pub fn wait(timeout_secs: u64) {
+    let shutdown = ShutdownProcess::get_process();
+    let timeout = Duration::from_secs(timeout_secs);
+    let mut started_at = None;
+    // a request that is left over from the previous run does not count
+    let _ = fs::remove_file(SHUTDOWN_REQUEST);
+    loop {
+        sleep(POLL_INTERVAL);
+        match shutdown.status() {
+            ShutdownState::Running => {
+                if Path::new(SHUTDOWN_REQUEST).exists() {
+                    lunatic_log::info!("[Shutdown] Found {}", SHUTDOWN_REQUEST);
+                    let _ = fs::remove_file(SHUTDOWN_REQUEST);
+                    shutdown.begin();
+                }
+            }
+            ShutdownState::Finished => return,
+            ShutdownState::ShuttingDown => {
+                let started_at = *started_at.get_or_insert_with(SystemTime::now);
+                if started_at.elapsed().unwrap_or_default() > timeout {
+                    lunatic_log::error!(
+                        "[Shutdown] Exceeded the timeout of {}s, exiting anyway",
+                        timeout_secs
+                    );
+                    return;
+                }
+            }
+        }
+    }
+}
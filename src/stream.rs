@@ -0,0 +1,84 @@
+use crate::config::ConnectionConfig;
+use crate::websocket::WsStream;
+use lunatic::net::{TcpStream, TlsStream};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Result as IoResult, Write};
+use std::net::IpAddr;
+use std::time::Duration;
+
+/// The transport a client is connected through. Client and writer processes
+/// only rely on `Read` and `Write` so that every listener can hand its
+/// connections to the same packet pipeline.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ClientStream {
+    Tcp(TcpStream),
+    Tls(TlsStream),
+    WebSocket(WsStream),
+}
+
+impl ClientStream {
+    /// name of the transport, used for logging
+    pub fn transport(&self) -> &'static str {
+        match self {
+            ClientStream::Tcp(_) => "tcp",
+            ClientStream::Tls(_) => "tls",
+            ClientStream::WebSocket(_) => "websocket",
+        }
+    }
+
+    /// reads fail with `TimedOut` if nothing arrives within the timeout
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> IoResult<()> {
+        match self {
+            ClientStream::Tcp(stream) => stream.set_read_timeout(timeout),
+            ClientStream::Tls(stream) => stream.set_read_timeout(timeout),
+            ClientStream::WebSocket(stream) => stream.set_read_timeout(timeout),
+        }
+    }
+}
+
+impl Read for ClientStream {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        match self {
+            ClientStream::Tcp(stream) => stream.read(buf),
+            ClientStream::Tls(stream) => stream.read(buf),
+            ClientStream::WebSocket(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for ClientStream {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        match self {
+            ClientStream::Tcp(stream) => stream.write(buf),
+            ClientStream::Tls(stream) => stream.write(buf),
+            ClientStream::WebSocket(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        match self {
+            ClientStream::Tcp(stream) => stream.flush(),
+            ClientStream::Tls(stream) => stream.flush(),
+            ClientStream::WebSocket(stream) => stream.flush(),
+        }
+    }
+}
+
+/// An accepted connection together with what the listener knows about the peer
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Connection {
+    pub stream: ClientStream,
+    /// address the client connected from
+    pub peer: IpAddr,
+    pub config: ConnectionConfig,
+}
+
+impl Connection {
+    pub fn new(stream: ClientStream, peer: IpAddr, config: ConnectionConfig) -> Connection {
+        Connection {
+            stream,
+            peer,
+            config,
+        }
+    }
+}
@@ -1,7 +1,11 @@
-use crate::client::{ClientProcess, WriterProcess};
+use crate::client::{ClientProcess, WriterProcess, WriterProcessHandler};
+use crate::config::SharedStrategy;
 use lunatic::process::ProcessRef;
-use mqtt_packet_3_5::{ConfirmationPacket, PublishPacket};
+use mqtt_packet_3_5::{
+    ConfirmationPacket, MqttPacket, PacketType, PubackPubrecCode, PublishPacket, Subscription,
+};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
 use std::time::SystemTime;
 use uuid::Uuid;
 
@@ -15,11 +19,200 @@ pub struct Queue {
     pub id: u128,
     pub name: String,
     pub subscribers: Vec<WriterRef>,
+    /// shared subscriptions, every message goes to one member of each group
+    pub groups: Vec<SharedGroup>,
+    /// options of the subscription of every session on this queue
+    pub options: HashMap<Uuid, SubscriptionOptions>,
+    /// subscription identifiers of every session by the filter they were set on
+    pub identifiers: HashMap<Uuid, BTreeMap<String, u32>>,
+}
+
+/// The v5 options of a subscription, v3 subscriptions use the defaults
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Debug, Default)]
+pub struct SubscriptionOptions {
+    pub qos: u8,
+    /// messages of the subscriber itself are not forwarded to it
+    pub no_local: bool,
+    /// forward the retain flag as it was published instead of clearing it
+    pub retain_as_published: bool,
+    /// 0 = send retained messages on subscribe, 1 = only for new
+    /// subscriptions, 2 = never
+    pub retain_handling: u8,
+}
+
+impl SubscriptionOptions {
+    pub fn from_subscription(subscription: &Subscription) -> SubscriptionOptions {
+        SubscriptionOptions {
+            // the broker supports every QoS, so the requested one is granted
+            qos: subscription.qos.min(2),
+            no_local: subscription.nl,
+            retain_as_published: subscription.rap,
+            retain_handling: subscription.rh.unwrap_or(0),
+        }
+    }
 }
 
 impl Queue {
     pub fn drop_inactive_subs(&mut self, inactive_subs: Vec<WriterRef>) {
+        // a session that went offline meanwhile keeps its subscription
+        let inactive_subs: Vec<WriterRef> = inactive_subs
+            .into_iter()
+            .filter(|sub| {
+                self.subscribers.contains(sub)
+                    || self.groups.iter().any(|group| group.members.contains(sub))
+            })
+            .collect();
+        for sub in inactive_subs.iter() {
+            self.options.remove(&sub.session_id);
+            self.identifiers.remove(&sub.session_id);
+        }
         self.subscribers.retain(|sub| !inactive_subs.contains(sub));
+        for group in self.groups.iter_mut() {
+            group
+                .members
+                .retain(|member| !inactive_subs.contains(member));
+        }
+        self.groups.retain(|group| !group.members.is_empty());
+    }
+
+    pub fn has_subscribers(&self) -> bool {
+        !self.subscribers.is_empty() || !self.groups.is_empty()
+    }
+
+    /// keep the receivers that can take another QoS 1 or 2 message and return
+    /// the subscribers and groups whose Receive Maximum is reached. Groups are
+    /// expected to only contain members with room, see `select_receivers`.
+    /// Subscriptions that were granted QoS 0 are never acknowledged, they
+    /// always have room
+    pub fn split_by_window(&mut self, inflight: impl Fn(&WriterRef) -> usize) -> CopyTarget {
+        let mut full = CopyTarget::default();
+        let options = &self.options;
+        self.subscribers.retain(|sub| {
+            let granted_qos = options.get(&sub.session_id).map_or(0, |o| o.qos);
+            let has_room = granted_qos == 0 || sub.has_window(inflight(sub));
+            if !has_room {
+                full.sessions.push(sub.session_id);
+            }
+            has_room
+        });
+        self.groups.retain(|group| {
+            if group.members.is_empty() {
+                full.groups.push(group.name.clone());
+            }
+            !group.members.is_empty()
+        });
+        full
+    }
+
+    /// remove the subscribers and selected group members whose session is
+    /// offline and return them, their messages are queued in the session
+    pub fn split_offline(&mut self) -> Vec<WriterRef> {
+        let mut offline = vec![];
+        let members = self.groups.iter_mut().map(|group| &mut group.members);
+        for subscribers in std::iter::once(&mut self.subscribers).chain(members) {
+            subscribers.retain(|sub| {
+                if sub.process.is_none() {
+                    offline.push(sub.clone());
+                }
+                sub.process.is_some()
+            });
+        }
+        self.groups.retain(|group| !group.members.is_empty());
+        offline
+    }
+
+    /// only keep the receivers of a copy of a message
+    pub fn restrict_to(&mut self, target: &CopyTarget) {
+        self.subscribers
+            .retain(|sub| target.sessions.contains(&sub.session_id));
+        self.groups
+            .retain(|group| target.groups.contains(&group.name));
+    }
+
+    pub fn options_of(&self, writer: &WriterRef) -> SubscriptionOptions {
+        self.options
+            .get(&writer.session_id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// identifiers of all subscriptions of the session that match this queue
+    pub fn identifiers_of(&self, writer: &WriterRef) -> Vec<u32> {
+        let mut identifiers: Vec<u32> = self
+            .identifiers
+            .get(&writer.session_id)
+            .map(|identifiers| identifiers.values().copied().collect())
+            .unwrap_or_default();
+        identifiers.sort_unstable();
+        identifiers
+    }
+
+    /// remember the identifier of a subscription, a subscription without
+    /// identifier removes the one it had before
+    pub fn set_identifier(&mut self, writer: &WriterRef, filter: &str, identifier: Option<u32>) {
+        let identifiers = self.identifiers.entry(writer.session_id).or_default();
+        match identifier {
+            Some(identifier) => identifiers.insert(filter.to_owned(), identifier),
+            None => identifiers.remove(filter),
+        };
+        if identifiers.is_empty() {
+            self.identifiers.remove(&writer.session_id);
+        }
+    }
+}
+
+/// The receivers of a copy of a message that the broker publishes itself
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug, Default)]
+pub struct CopyTarget {
+    pub sessions: Vec<Uuid>,
+    /// shared subscription groups, one of their members receives the copy
+    pub groups: Vec<String>,
+    /// a retained message for a new subscription, it keeps its retain flag
+    pub retained: bool,
+}
+
+impl CopyTarget {
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty() && self.groups.is_empty()
+    }
+}
+
+/// The members of a `$share/<group>/<filter>` subscription on a queue
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub struct SharedGroup {
+    pub name: String,
+    pub members: Vec<WriterRef>,
+    /// position of the next member for round-robin delivery
+    pub next: usize,
+}
+
+impl SharedGroup {
+    /// order the members by preference. The first one receives the message,
+    /// the others are tried in turn if it is offline
+    pub fn select(
+        &mut self,
+        strategy: SharedStrategy,
+        inflight: impl Fn(&WriterRef) -> usize,
+    ) -> Vec<WriterRef> {
+        if self.members.is_empty() {
+            return vec![];
+        }
+        let first = match strategy {
+            SharedStrategy::RoundRobin => {
+                let first = self.next % self.members.len();
+                self.next = first + 1;
+                first
+            }
+            SharedStrategy::Random => {
+                (Uuid::new_v4().as_u128() % self.members.len() as u128) as usize
+            }
+            SharedStrategy::LeastInflight => (0..self.members.len())
+                .min_by_key(|i| inflight(&self.members[*i]))
+                .unwrap_or(0),
+        };
+        let mut members = self.members.clone();
+        members.rotate_left(first);
+        members
     }
 }
 
@@ -57,20 +250,176 @@ pub struct PublishContext {
     pub receivers: Vec<Receiver>,
     pub sender: WriterRef,
     pub started_at: SystemTime,
+    /// the message is dropped if it was not sent before this point in time
+    pub expires_at: Option<SystemTime>,
+    /// set for the copies the broker publishes for retained messages of new
+    /// subscriptions and for subscribers whose Receive Maximum was reached,
+    /// no other subscriber of the topic receives them
+    pub copy_for: Option<CopyTarget>,
+}
+
+impl PublishContext {
+    /// the packet as it is forwarded to subscribers. The expiry interval
+    /// is reduced by the time the message has been waiting in the broker
+    pub fn forwarded_packet(&self, now: SystemTime) -> PublishPacket {
+        let mut packet = self.packet.clone();
+        set_remaining_expiry(&mut packet, self.expires_at, now);
+        packet
+    }
+}
+
+/// set the expiry interval of a packet to the time that is left until it expires
+pub fn set_remaining_expiry(
+    packet: &mut PublishPacket,
+    expires_at: Option<SystemTime>,
+    now: SystemTime,
+) {
+    if let Some(expires_at) = expires_at {
+        let remaining = expires_at.duration_since(now).unwrap_or_default();
+        packet
+            .properties
+            .get_or_insert_with(Default::default)
+            .message_expiry_interval = Some(remaining.as_secs().max(1) as u32);
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct WriterRef {
     pub process: Option<ProcessRef<WriterProcess>>,
     pub client_id: String,
+    pub username: Option<String>,
+    pub protocol_version: u8,
+    /// seconds within which the client has to send a packet, 0 disables it
+    pub keep_alive: u16,
     pub session_id: Uuid,
     pub is_persistent_session: bool,
+    /// number of unacknowledged QoS 1 and 2 messages the client accepts
+    pub receive_maximum: u16,
+    /// largest packet the client accepts, larger publishes are not sent to it
+    pub maximum_packet_size: Option<u32>,
+}
+
+/// client id of the messages that are published through the admin API
+pub const BROKER_CLIENT_ID: &str = "$broker";
+
+impl WriterRef {
+    /// the publisher of messages that are injected through the admin API and
+    /// of retained messages for new subscriptions. It has no connection and
+    /// the nil session id, which no client can have
+    pub fn broker() -> WriterRef {
+        WriterRef {
+            process: None,
+            client_id: BROKER_CLIENT_ID.to_owned(),
+            username: None,
+            protocol_version: 5,
+            keep_alive: 0,
+            session_id: Uuid::nil(),
+            is_persistent_session: false,
+            receive_maximum: u16::MAX,
+            maximum_packet_size: None,
+        }
+    }
+
+    pub fn is_broker(&self) -> bool {
+        self.session_id.is_nil()
+    }
+
+    /// whether the client can receive another QoS 1 or 2 message while it
+    /// has not acknowledged `inflight` messages
+    pub fn has_window(&self, inflight: usize) -> bool {
+        inflight < self.receive_maximum as usize
+    }
+
+    /// the reference that stays in the topic tree and the message store
+    /// while the session is offline, nothing can be written to it
+    pub fn offline(&self) -> WriterRef {
+        WriterRef {
+            process: None,
+            ..self.clone()
+        }
+    }
+
+    /// acknowledgements can be sent to the publisher. Recovered messages wait
+    /// until their publisher reconnected, the broker acknowledges itself
+    pub fn is_reachable(&self) -> bool {
+        self.process.is_some() || self.is_broker()
+    }
+
+    /// send a PUBACK, PUBREC or PUBCOMP to the publisher of a message
+    pub fn acknowledge(&self, packet: MqttPacket) -> bool {
+        match &self.process {
+            Some(process) => process.write_packet(packet),
+            None => self.is_broker(),
+        }
+    }
+
+    /// v5 clients can limit the size of packets they receive, publishes that
+    /// are larger are not sent to them at all
+    pub fn exceeds_maximum_packet_size(&self, packet: &PublishPacket) -> bool {
+        let maximum = match self.maximum_packet_size {
+            Some(maximum) => maximum as usize,
+            None => return false,
+        };
+        let size = MqttPacket::Publish(packet.clone())
+            .encode(self.protocol_version)
+            .map_or(0, |encoded| encoded.len());
+        if size > maximum {
+            lunatic_log::debug!(
+                "[Writer {}] Skipping publish of {} bytes, the maximum packet size is {}",
+                self.client_id,
+                size,
+                maximum
+            );
+        }
+        size > maximum
+    }
+
+    /// acknowledge a publish whose QoS flow the broker completes itself
+    pub fn accept_publish(&self, packet: &PublishPacket) -> bool {
+        self.is_broker() || self.reject_publish(packet, PubackPubrecCode::Success)
+    }
+
+    /// answer a publish that will not be delivered. QoS 0 messages are simply dropped,
+    /// v5 clients receive the reason code in the PUBACK/PUBREC while v3 clients
+    /// only get a regular acknowledgement because they have no way of handling errors
+    pub fn reject_publish(&self, packet: &PublishPacket, reason_code: PubackPubrecCode) -> bool {
+        let message_id = match packet.message_id {
+            Some(id) if packet.qos > 0 => id,
+            _ => return true,
+        };
+        let confirmation = ConfirmationPacket {
+            cmd: if packet.qos == 1 {
+                PacketType::Puback
+            } else {
+                PacketType::Pubrec
+            },
+            message_id,
+            puback_reason_code: if self.protocol_version == 5 {
+                Some(reason_code)
+            } else {
+                None
+            },
+            pubcomp_reason_code: None,
+            properties: None,
+        };
+        let wrapped_packet = if packet.qos == 1 {
+            MqttPacket::Puback(confirmation)
+        } else {
+            MqttPacket::Pubrec(confirmation)
+        };
+        match &self.process {
+            Some(process) => process.write_packet(wrapped_packet),
+            None => false,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct PublishJob {
     pub message: PublishMessage,
     pub queue: Queue,
+    /// offline sessions that the message is queued for instead of being sent
+    pub offline: Vec<WriterRef>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -115,5 +464,34 @@ pub struct Client {
     // username: String,
     pub client: ProcessRef<ClientProcess>,
     pub writer: WriterRef,
-    pub should_persist: bool,
+    /// seconds the session outlives the connection, `SESSION_NEVER_EXPIRES` keeps it forever
+    pub session_expiry_interval: u32,
+}
+
+/// session expiry interval of sessions that are kept until the client cleans them up
+pub const SESSION_NEVER_EXPIRES: u32 = u32::MAX;
+
+/// The state of a disconnected client that is kept until the session expires
+#[derive(Debug)]
+pub struct OfflineSession {
+    pub writer: WriterRef,
+    pub expires_at: Option<SystemTime>,
+    /// QoS 1 and 2 messages that were published while the client was offline
+    pub queued: Vec<OfflineMessage>,
+}
+
+/// A message that waits for an offline session, it is queued as a copy for
+/// the session once it resumes
+#[derive(Debug)]
+pub struct OfflineMessage {
+    pub packet: PublishPacket,
+    pub expires_at: Option<SystemTime>,
+    /// a retained message for a subscription of the session
+    pub retained: bool,
+}
+
+impl OfflineSession {
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
 }
@@ -0,0 +1,139 @@
+use crate::reason_code;
+use mqtt_packet_3_5::PublishPacket;
+use std::collections::HashMap;
+
+/// number of topic aliases a client may set on a single connection
+pub const TOPIC_ALIAS_MAXIMUM: u16 = 64;
+
+/// Aliases a client set for the topics it publishes to. They only live as
+/// long as the connection
+#[derive(Debug, Default)]
+pub struct InboundAliases {
+    maximum: u16,
+    topics: HashMap<u16, String>,
+}
+
+impl InboundAliases {
+    pub fn new(maximum: u16) -> InboundAliases {
+        InboundAliases {
+            maximum,
+            topics: HashMap::new(),
+        }
+    }
+
+    /// replace the alias of the packet with the topic it stands for. A packet with
+    /// topic and alias sets the alias, one with an empty topic uses it
+    pub fn resolve(&mut self, packet: &mut PublishPacket) -> Result<(), u8> {
+        let alias = match packet
+            .properties
+            .as_mut()
+            .and_then(|properties| properties.topic_alias.take())
+        {
+            Some(alias) => alias,
+            None => return Ok(()),
+        };
+        if alias == 0 || alias > self.maximum {
+            return Err(reason_code::TOPIC_ALIAS_INVALID);
+        }
+        if packet.topic.is_empty() {
+            packet.topic = self
+                .topics
+                .get(&alias)
+                .cloned()
+                .ok_or(reason_code::TOPIC_ALIAS_INVALID)?;
+        } else {
+            self.topics.insert(alias, packet.topic.clone());
+        }
+        Ok(())
+    }
+}
+
+/// Aliases the broker assigns to the topics it forwards to a client, limited
+/// by the Topic Alias Maximum of its CONNECT. Once all are used up the
+/// remaining topics are sent in full
+#[derive(Debug, Default)]
+pub struct OutboundAliases {
+    maximum: u16,
+    aliases: HashMap<String, u16>,
+}
+
+impl OutboundAliases {
+    pub fn new(maximum: u16) -> OutboundAliases {
+        OutboundAliases {
+            maximum,
+            aliases: HashMap::new(),
+        }
+    }
+
+    /// set the alias of the packet, the topic is only sent the first time
+    pub fn apply(&mut self, packet: &mut PublishPacket) {
+        let properties = packet.properties.get_or_insert_with(Default::default);
+        // aliases of the publisher are not valid on this connection
+        properties.topic_alias = None;
+        if let Some(alias) = self.aliases.get(&packet.topic) {
+            properties.topic_alias = Some(*alias);
+            packet.topic = String::new();
+        } else if (self.aliases.len() as u16) < self.maximum {
+            let alias = self.aliases.len() as u16 + 1;
+            self.aliases.insert(packet.topic.clone(), alias);
+            properties.topic_alias = Some(alias);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn publish(topic: &str, alias: Option<u16>) -> PublishPacket {
+        PublishPacket {
+            dup: false,
+            qos: 0,
+            retain: false,
+            topic: topic.to_owned(),
+            message_id: None,
+            payload: vec![],
+            properties: Some(mqtt_packet_3_5::PublishProperties {
+                topic_alias: alias,
+                ..Default::default()
+            }),
+        }
+    }
+
+    #[test]
+    fn inbound_aliases() {
+        let mut aliases = InboundAliases::new(2);
+        let mut packet = publish("sensors/1/temperature", Some(1));
+        assert_eq!(aliases.resolve(&mut packet), Ok(()));
+        let mut packet = publish("", Some(1));
+        assert_eq!(aliases.resolve(&mut packet), Ok(()));
+        assert_eq!(packet.topic, "sensors/1/temperature");
+        assert_eq!(packet.properties.unwrap().topic_alias, None);
+
+        let invalid = reason_code::TOPIC_ALIAS_INVALID;
+        assert_eq!(aliases.resolve(&mut publish("", Some(2))), Err(invalid));
+        assert_eq!(aliases.resolve(&mut publish("a", Some(0))), Err(invalid));
+        assert_eq!(aliases.resolve(&mut publish("a", Some(3))), Err(invalid));
+        assert_eq!(aliases.resolve(&mut publish("a", None)), Ok(()));
+    }
+
+    #[test]
+    fn outbound_aliases() {
+        let mut aliases = OutboundAliases::new(1);
+        let mut first = publish("a/b", Some(7));
+        aliases.apply(&mut first);
+        assert_eq!(first.topic, "a/b");
+        assert_eq!(first.properties.unwrap().topic_alias, Some(1));
+
+        let mut second = publish("a/b", None);
+        aliases.apply(&mut second);
+        assert_eq!(second.topic, "");
+        assert_eq!(second.properties.unwrap().topic_alias, Some(1));
+
+        // all aliases are in use
+        let mut other = publish("c", None);
+        aliases.apply(&mut other);
+        assert_eq!(other.topic, "c");
+        assert_eq!(other.properties.unwrap().topic_alias, None);
+    }
+}
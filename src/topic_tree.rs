@@ -1,10 +1,20 @@
+use crate::config::SharedStrategy;
 use crate::structure::*;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+/// prefix of shared subscriptions, `$share/<group>/<filter>`
+pub const SHARED_PREFIX: &str = "$share/";
 
 #[derive(Default, Debug)]
 pub struct TopicTree {
     counter: u128,
     queues: HashMap<String, Queue>,
+    /// filters of the subscriptions of every session, a subscription is
+    /// new if the session did not subscribe with the same filter before
+    filters: HashMap<Uuid, HashSet<String>>,
+    /// how a member of a shared subscription group is chosen
+    pub strategy: SharedStrategy,
 }
 
 #[derive(PartialEq, Debug)]
@@ -267,6 +277,9 @@ impl TopicTree {
                 id,
                 name: topic.to_string(),
                 subscribers: Vec::new(),
+                groups: Vec::new(),
+                options: HashMap::new(),
+                identifiers: HashMap::new(),
             };
             self.queues.insert(topic.to_string(), queue);
             // add subscribers with matching topics/wildcards
@@ -308,17 +321,242 @@ impl TopicTree {
         //     .collect::<Vec<Queue>>()
     }
 
-    pub fn add_subscriptions(&mut self, topic: String, writer: WriterRef) {
+    pub fn queues(&self) -> impl Iterator<Item = &Queue> {
+        self.queues.values()
+    }
+
+    /// the topics a session receives messages of, shared subscriptions
+    /// are prefixed with `$share/<group>/`
+    pub fn subscriptions_of(&self, session_id: Uuid) -> Vec<String> {
+        let mut subscriptions = vec![];
+        for queue in self.queues.values() {
+            if queue
+                .subscribers
+                .iter()
+                .any(|sub| sub.session_id == session_id)
+            {
+                subscriptions.push(queue.name.clone());
+            }
+            for group in queue.groups.iter() {
+                if group.members.iter().any(|m| m.session_id == session_id) {
+                    subscriptions.push(format!("{}{}/{}", SHARED_PREFIX, group.name, queue.name));
+                }
+            }
+        }
+        subscriptions.sort();
+        subscriptions
+    }
+
+    /// remove all subscriptions of a session
+    pub fn remove_subscriber(&mut self, writer: &WriterRef) {
+        self.filters.remove(&writer.session_id);
+        for queue in self.queues.values_mut() {
+            queue.options.remove(&writer.session_id);
+            queue.identifiers.remove(&writer.session_id);
+            queue
+                .subscribers
+                .retain(|sub| sub.session_id != writer.session_id);
+            for group in queue.groups.iter_mut() {
+                group
+                    .members
+                    .retain(|member| member.session_id != writer.session_id);
+            }
+            queue.groups.retain(|group| !group.members.is_empty());
+        }
+    }
+
+    /// move all subscriptions of a session to a new session of the same client
+    pub fn replace_subscriber(&mut self, previous: &WriterRef, writer: WriterRef) {
+        if let Some(filters) = self.filters.remove(&previous.session_id) {
+            self.filters.insert(writer.session_id, filters);
+        }
+        for queue in self.queues.values_mut() {
+            if let Some(options) = queue.options.remove(&previous.session_id) {
+                queue.options.insert(writer.session_id, options);
+            }
+            if let Some(identifiers) = queue.identifiers.remove(&previous.session_id) {
+                queue.identifiers.insert(writer.session_id, identifiers);
+            }
+            let members = queue
+                .groups
+                .iter_mut()
+                .flat_map(|group| group.members.iter_mut());
+            for sub in queue.subscribers.iter_mut().chain(members) {
+                if sub.session_id == previous.session_id {
+                    *sub = writer.clone();
+                }
+            }
+        }
+    }
+
+    /// add the session to the group on every queue that matches the filter
+    pub fn add_shared_subscription(
+        &mut self,
+        group: &str,
+        filter: &str,
+        writer: WriterRef,
+        options: SubscriptionOptions,
+        identifier: Option<u32>,
+    ) {
+        for q in self.get_matching_queue_names(filter) {
+            let queue = self.queues.get_mut(&q).unwrap();
+            queue.options.insert(writer.session_id, options);
+            // the group is part of the filter, so that identifiers of a shared and
+            // a regular subscription with the same filter are kept apart
+            queue.set_identifier(
+                &writer,
+                &format!("{}{}/{}", SHARED_PREFIX, group, filter),
+                identifier,
+            );
+            let index = match queue.groups.iter().position(|g| g.name == group) {
+                Some(index) => index,
+                None => {
+                    queue.groups.push(SharedGroup {
+                        name: group.to_owned(),
+                        members: vec![],
+                        next: 0,
+                    });
+                    queue.groups.len() - 1
+                }
+            };
+            let members = &mut queue.groups[index].members;
+            if !members.iter().any(|m| m.session_id == writer.session_id) {
+                members.push(writer.clone());
+            }
+        }
+    }
+
+    /// a copy of the queue for delivering a message. The members of every
+    /// group are ordered so that the selected one comes first, members whose
+    /// Receive Maximum is reached are left out for QoS 1 and 2 messages
+    pub fn select_receivers(
+        &mut self,
+        queue_id: u128,
+        qos: u8,
+        inflight: impl Fn(&WriterRef) -> usize,
+    ) -> Queue {
+        let strategy = self.strategy;
+        let queue = self.get_by_id(queue_id);
+        let mut selected = queue.clone();
+        let options = &selected.options;
+        for (group, selection) in queue.groups.iter_mut().zip(selected.groups.iter_mut()) {
+            selection.members = group.select(strategy, &inflight);
+            if qos > 0 {
+                selection.members.retain(|member| {
+                    let granted_qos = options.get(&member.session_id).map_or(0, |o| o.qos);
+                    granted_qos == 0 || member.has_window(inflight(member))
+                });
+            }
+        }
+        selected
+    }
+
+    /// subscribe the session to every queue that matches the topic. A repeated
+    /// subscription only replaces the options, returns true if the session did
+    /// not subscribe with this filter before
+    pub fn add_subscriptions(
+        &mut self,
+        topic: String,
+        writer: WriterRef,
+        options: SubscriptionOptions,
+        identifier: Option<u32>,
+    ) -> bool {
+        let is_new = self
+            .filters
+            .entry(writer.session_id)
+            .or_default()
+            .insert(topic.clone());
         for q in self.get_matching_queue_names(&topic) {
-            self.queues
-                .get_mut(&q)
-                .unwrap()
+            let queue = self.queues.get_mut(&q).unwrap();
+            queue.options.insert(writer.session_id, options);
+            queue.set_identifier(&writer, &topic, identifier);
+            if !queue
                 .subscribers
-                .push(writer.clone());
+                .iter()
+                .any(|sub| sub.session_id == writer.session_id)
+            {
+                queue.subscribers.push(writer.clone());
+            }
+        }
+        is_new
+    }
+}
+
+/// longest topic name or filter in bytes that the broker accepts
+pub const MAX_TOPIC_LENGTH: usize = 4096;
+/// maximum number of levels of a topic name or filter
+pub const MAX_TOPIC_LEVELS: usize = 64;
+
+#[derive(Debug, PartialEq)]
+pub enum TopicError {
+    Empty,
+    TooLong,
+    TooManyLevels,
+    NulCharacter,
+    /// topic names must not contain wildcards
+    Wildcard,
+    /// `#` has to be the last level and `+` has to occupy a whole level
+    InvalidWildcard,
+    /// the group of a shared subscription is empty or contains wildcards
+    InvalidShareName,
+}
+
+fn validate_topic(topic: &str) -> Result<(), TopicError> {
+    if topic.is_empty() {
+        return Err(TopicError::Empty);
+    }
+    if topic.len() > MAX_TOPIC_LENGTH {
+        return Err(TopicError::TooLong);
+    }
+    if topic.contains('\0') {
+        return Err(TopicError::NulCharacter);
+    }
+    if topic.split('/').count() > MAX_TOPIC_LEVELS {
+        return Err(TopicError::TooManyLevels);
+    }
+    Ok(())
+}
+
+/// check the topic of a PUBLISH
+pub fn validate_topic_name(topic: &str) -> Result<(), TopicError> {
+    validate_topic(topic)?;
+    if topic.contains(&['+', '#'][..]) {
+        return Err(TopicError::Wildcard);
+    }
+    Ok(())
+}
+
+/// split a `$share/<group>/<filter>` subscription into its group and filter,
+/// other filters are returned without a group
+pub fn split_shared_filter(filter: &str) -> Result<(Option<&str>, &str), TopicError> {
+    let shared = match filter.strip_prefix(SHARED_PREFIX) {
+        Some(shared) => shared,
+        None => return Ok((None, filter)),
+    };
+    match shared.split_once('/') {
+        Some((group, filter)) if !group.is_empty() && !group.contains(&['+', '#'][..]) => {
+            Ok((Some(group), filter))
         }
+        _ => Err(TopicError::InvalidShareName),
     }
 }
 
+/// check the topic filter of a subscription
+pub fn validate_topic_filter(filter: &str) -> Result<(), TopicError> {
+    validate_topic(filter)?;
+    let mut levels = filter.split('/').peekable();
+    while let Some(level) = levels.next() {
+        let is_last = levels.peek().is_none();
+        match level {
+            "+" => {}
+            "#" if is_last => {}
+            level if level.contains(&['+', '#'][..]) => return Err(TopicError::InvalidWildcard),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
 #[cfg(test)]
 mod simple_tests {
     use super::*;
@@ -449,4 +687,231 @@ mod simple_tests {
         assert_eq!(State::match_topic("#", "/finance/"), false);
         assert_eq!(State::match_topic("#", "/finance/hello+"), false);
     }
+
+    #[test]
+    fn validate_topic_names() {
+        assert_eq!(validate_topic_name("finance/stocks"), Ok(()));
+        assert_eq!(validate_topic_name("/finance/"), Ok(()));
+        assert_eq!(validate_topic_name(""), Err(TopicError::Empty));
+        assert_eq!(validate_topic_name("finance/+"), Err(TopicError::Wildcard));
+        assert_eq!(validate_topic_name("finance/#"), Err(TopicError::Wildcard));
+        assert_eq!(
+            validate_topic_name("finance\0"),
+            Err(TopicError::NulCharacter)
+        );
+        assert_eq!(
+            validate_topic_name(&"a".repeat(MAX_TOPIC_LENGTH + 1)),
+            Err(TopicError::TooLong)
+        );
+        assert_eq!(
+            validate_topic_name(&"a/".repeat(MAX_TOPIC_LEVELS)),
+            Err(TopicError::TooManyLevels)
+        );
+    }
+
+    #[test]
+    fn validate_topic_filters() {
+        for filter in ["#", "+", "+/+", "/+", "finance/#", "users/+/device/#", "/"] {
+            assert_eq!(validate_topic_filter(filter), Ok(()), "{}", filter);
+        }
+        for filter in ["#/+", "finance/#e", "#+", "#/#", "finance+", "fin#ance/x"] {
+            assert_eq!(
+                validate_topic_filter(filter),
+                Err(TopicError::InvalidWildcard),
+                "{}",
+                filter
+            );
+        }
+        assert_eq!(validate_topic_filter(""), Err(TopicError::Empty));
+    }
+
+    #[test]
+    fn shared_filters() {
+        assert_eq!(split_shared_filter("a/b"), Ok((None, "a/b")));
+        assert_eq!(
+            split_shared_filter("$share/workers/jobs/#"),
+            Ok((Some("workers"), "jobs/#"))
+        );
+        for filter in ["$share/workers", "$share//jobs", "$share/w+/jobs"] {
+            assert_eq!(
+                split_shared_filter(filter),
+                Err(TopicError::InvalidShareName),
+                "{}",
+                filter
+            );
+        }
+    }
+
+    /// a tree with the queue of `topic`, the subscription tests all start with it
+    fn tree_with(topic: &str) -> (TopicTree, u128) {
+        let mut tree = TopicTree::default();
+        let queue_id = tree.get_by_name(topic.to_owned()).id;
+        (tree, queue_id)
+    }
+
+    fn writer(client_id: &str) -> WriterRef {
+        WriterRef {
+            process: None,
+            client_id: client_id.to_owned(),
+            username: None,
+            protocol_version: 5,
+            keep_alive: 60,
+            session_id: Uuid::new_v4(),
+            is_persistent_session: false,
+            receive_maximum: 2,
+            maximum_packet_size: None,
+        }
+    }
+
+    #[test]
+    fn shared_subscriptions() {
+        let (mut tree, queue_id) = tree_with("jobs/1");
+        let (first, second) = (writer("first"), writer("second"));
+        let options = SubscriptionOptions::default();
+        tree.add_shared_subscription("workers", "jobs/+", first.clone(), options, None);
+        tree.add_shared_subscription("workers", "jobs/+", second.clone(), options, None);
+        tree.add_shared_subscription("workers", "jobs/+", second.clone(), options, None);
+
+        let order = |tree: &mut TopicTree, inflight: usize| {
+            let queue = tree.select_receivers(queue_id, 0, |w| {
+                if w.session_id == first.session_id {
+                    inflight
+                } else {
+                    1
+                }
+            });
+            assert_eq!(queue.groups.len(), 1);
+            queue.groups[0]
+                .members
+                .iter()
+                .map(|m| m.client_id.clone())
+                .collect::<Vec<_>>()
+        };
+        // every member takes its turn, the others follow for failover
+        assert_eq!(order(&mut tree, 0), ["first", "second"]);
+        assert_eq!(order(&mut tree, 0), ["second", "first"]);
+        assert_eq!(order(&mut tree, 0), ["first", "second"]);
+
+        tree.strategy = SharedStrategy::LeastInflight;
+        assert_eq!(order(&mut tree, 2), ["second", "first"]);
+        assert_eq!(order(&mut tree, 0), ["first", "second"]);
+
+        tree.remove_subscriber(&first);
+        tree.remove_subscriber(&second);
+        assert!(!tree.get_by_id(queue_id).has_subscribers());
+    }
+
+    #[test]
+    fn session_subscriptions() {
+        let (mut tree, _) = tree_with("jobs/1");
+        let subscriber = writer("worker");
+        let options = SubscriptionOptions::default();
+        tree.add_shared_subscription("workers", "jobs/+", subscriber.clone(), options, None);
+        tree.add_subscriptions("jobs/1".to_owned(), subscriber.clone(), options, None);
+        assert_eq!(
+            tree.subscriptions_of(subscriber.session_id),
+            ["$share/workers/jobs/1", "jobs/1"]
+        );
+    }
+
+    #[test]
+    fn receive_maximum() {
+        let (mut tree, queue_id) = tree_with("jobs");
+        let (first, second, third) = (writer("first"), writer("second"), writer("third"));
+        let options = SubscriptionOptions {
+            qos: 1,
+            ..Default::default()
+        };
+        tree.add_shared_subscription("workers", "jobs", first.clone(), options, None);
+        tree.add_shared_subscription("workers", "jobs", second.clone(), options, None);
+        let full = |w: &WriterRef| {
+            if w.session_id == second.session_id {
+                0
+            } else {
+                2
+            }
+        };
+
+        // the member with a full window is skipped for QoS 1 and 2
+        let queue = tree.select_receivers(queue_id, 1, full);
+        assert_eq!(queue.groups[0].members, vec![second.clone()]);
+        let queue = tree.select_receivers(queue_id, 0, full);
+        assert_eq!(queue.groups[0].members.len(), 2);
+
+        // the message only waits for the subscribers whose window is full
+        tree.add_subscriptions("jobs".to_owned(), first.clone(), options, None);
+        tree.add_subscriptions("jobs".to_owned(), second.clone(), options, None);
+        tree.add_subscriptions("jobs".to_owned(), third.clone(), Default::default(), None);
+        let mut queue = tree.select_receivers(queue_id, 1, full);
+        let deferred = queue.split_by_window(full);
+        // QoS 0 subscriptions are never acknowledged and always have room
+        assert_eq!(queue.subscribers, vec![second.clone(), third.clone()]);
+        assert_eq!(deferred.sessions, vec![first.session_id]);
+        assert!(deferred.groups.is_empty());
+
+        // a group without a member that has room waits as a whole
+        let mut queue = tree.select_receivers(queue_id, 1, |_| 2);
+        let deferred = queue.split_by_window(|_| 2);
+        assert_eq!(queue.subscribers, vec![third]);
+        assert_eq!(deferred.sessions, vec![first.session_id, second.session_id]);
+        assert_eq!(deferred.groups, ["workers"]);
+        let mut copy = tree.get_by_id(queue_id).clone();
+        copy.restrict_to(&deferred);
+        assert_eq!(copy.subscribers, vec![first, second]);
+        assert_eq!(copy.groups.len(), 1);
+    }
+
+    #[test]
+    fn subscription_options() {
+        let (mut tree, queue_id) = tree_with("a/b");
+        let (previous, subscriber) = (writer("client"), writer("client"));
+        let options = SubscriptionOptions {
+            qos: 1,
+            no_local: true,
+            ..Default::default()
+        };
+        assert!(tree.add_subscriptions("a/+".to_owned(), previous.clone(), options, None));
+        // another filter on the same topic is a new subscription of its own
+        assert!(tree.add_subscriptions("a/b".to_owned(), previous.clone(), options, None));
+        let options = SubscriptionOptions {
+            retain_as_published: true,
+            ..options
+        };
+        // a repeated subscription replaces the options
+        assert!(!tree.add_subscriptions("a/+".to_owned(), previous.clone(), options, None));
+        let queue = tree.get_by_id(queue_id);
+        assert_eq!(queue.subscribers.len(), 1);
+        assert_eq!(queue.options_of(&previous), options);
+
+        tree.replace_subscriber(&previous, subscriber.clone());
+        let queue = tree.get_by_id(queue_id);
+        assert_eq!(queue.options_of(&subscriber), options);
+        assert_eq!(queue.options_of(&previous), SubscriptionOptions::default());
+        // the resumed session still knows its filters
+        assert!(!tree.add_subscriptions("a/b".to_owned(), subscriber.clone(), options, None));
+    }
+
+    #[test]
+    fn subscription_identifiers() {
+        let (mut tree, queue_id) = tree_with("a/b");
+        let subscriber = writer("client");
+        let options = SubscriptionOptions::default();
+        tree.add_subscriptions("a/+".to_owned(), subscriber.clone(), options, Some(1));
+        tree.add_subscriptions("a/#".to_owned(), subscriber.clone(), options, Some(2));
+        tree.add_shared_subscription("g", "a/b", subscriber.clone(), options, Some(3));
+        assert_eq!(
+            tree.get_by_id(queue_id).identifiers_of(&subscriber),
+            [1, 2, 3]
+        );
+
+        // subscribing again without identifier removes it
+        tree.add_subscriptions("a/#".to_owned(), subscriber.clone(), options, None);
+        assert_eq!(tree.get_by_id(queue_id).identifiers_of(&subscriber), [1, 3]);
+
+        tree.remove_subscriber(&subscriber);
+        assert!(tree
+            .get_by_id(queue_id)
+            .identifiers_of(&subscriber)
+            .is_empty());
+    }
 }
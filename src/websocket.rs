@@ -0,0 +1,354 @@
+use crate::client::{WriterProcess, WriterProcessHandler};
+use lunatic::net::TcpStream;
+use lunatic::process::ProcessRef;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+use std::io::{Error, ErrorKind, Read, Result as IoResult, Write};
+use std::time::{Duration, SystemTime};
+
+/// magic value from RFC 6455 that is appended to the key of the client
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// upper bound for the http upgrade request
+const MAX_HANDSHAKE_SIZE: usize = 8 * 1024;
+/// a client that did not finish the upgrade request by then is dropped
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const OPCODE_CONTINUATION: u8 = 0x0;
+const OPCODE_BINARY: u8 = 0x2;
+pub const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+const OPCODE_PONG: u8 = 0xA;
+
+#[derive(Debug, PartialEq)]
+pub struct Frame {
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+/// A websocket connection that carries MQTT packets in binary frames.
+/// Reading yields the concatenated frame payloads, every write is sent
+/// as a single binary frame.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WsStream {
+    stream: TcpStream,
+    path: String,
+    /// frames are read into memory completely, larger ones are refused
+    max_frame_size: usize,
+    /// payload of the last frame that has not been read yet
+    pending: Vec<u8>,
+    closed: bool,
+    /// once the writer of the client runs, replies to control frames are
+    /// sent through it so that they cannot interleave with its frames
+    writer: Option<ProcessRef<WriterProcess>>,
+}
+
+impl WsStream {
+    pub fn new(stream: TcpStream, path: String, max_frame_size: usize) -> WsStream {
+        WsStream {
+            stream,
+            path,
+            max_frame_size,
+            pending: vec![],
+            closed: false,
+            writer: None,
+        }
+    }
+
+    /// read the http upgrade request and answer it. Only requests for the
+    /// configured path that offer the `mqtt` subprotocol are accepted
+    pub fn handshake(&mut self) -> IoResult<()> {
+        let request = self.read_upgrade_request()?;
+        self.stream.set_read_timeout(None)?;
+        match accept_response(&request, &self.path) {
+            Ok(response) => self.stream.write_all(response.as_bytes()),
+            Err(reason) => {
+                let _ = self.stream.write_all(
+                    format!(
+                        "HTTP/1.1 400 Bad Request\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        reason.len(),
+                        reason
+                    )
+                    .as_bytes(),
+                );
+                Err(Error::new(ErrorKind::InvalidData, reason))
+            }
+        }
+    }
+
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> IoResult<()> {
+        self.stream.set_read_timeout(timeout)
+    }
+
+    /// send the replies to control frames through the writer of the client
+    pub fn attach_writer(&mut self, writer: ProcessRef<WriterProcess>) {
+        self.writer = Some(writer);
+    }
+
+    /// write a control frame directly to the socket, only the writer may
+    /// do this once it is attached
+    pub fn write_control_frame(&mut self, opcode: u8, payload: &[u8]) -> IoResult<()> {
+        self.stream.write_all(&encode_frame(opcode, payload))
+    }
+
+    fn reply(&mut self, opcode: u8, payload: Vec<u8>) -> IoResult<()> {
+        match &self.writer {
+            Some(writer) => {
+                writer.write_control_frame(opcode, payload);
+                Ok(())
+            }
+            None => self.write_control_frame(opcode, &payload),
+        }
+    }
+
+    /// the whole request has to arrive within `HANDSHAKE_TIMEOUT`, not
+    /// just every single read
+    fn read_upgrade_request(&mut self) -> IoResult<String> {
+        let deadline = SystemTime::now() + HANDSHAKE_TIMEOUT;
+        let mut request = Vec::with_capacity(512);
+        let mut byte = [0u8; 1];
+        while !request.ends_with(b"\r\n\r\n") {
+            if request.len() >= MAX_HANDSHAKE_SIZE {
+                return Err(Error::new(ErrorKind::InvalidData, "handshake too large"));
+            }
+            let remaining = deadline
+                .duration_since(SystemTime::now())
+                .ok()
+                .filter(|remaining| !remaining.is_zero())
+                .ok_or_else(|| Error::new(ErrorKind::TimedOut, "handshake timed out"))?;
+            self.stream.set_read_timeout(Some(remaining))?;
+            if self.stream.read(&mut byte)? == 0 {
+                return Err(Error::new(ErrorKind::UnexpectedEof, "handshake incomplete"));
+            }
+            request.push(byte[0]);
+        }
+        String::from_utf8(request).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+}
+
+impl Read for WsStream {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
+        while self.pending.is_empty() {
+            if self.closed {
+                return Ok(0);
+            }
+            let frame = read_frame(&mut self.stream, self.max_frame_size)?;
+            match frame.opcode {
+                OPCODE_BINARY | OPCODE_CONTINUATION => self.pending = frame.payload,
+                OPCODE_PING => self.reply(OPCODE_PONG, frame.payload)?,
+                OPCODE_PONG => {}
+                OPCODE_CLOSE => {
+                    let _ = self.reply(OPCODE_CLOSE, vec![]);
+                    self.closed = true;
+                }
+                other => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("MQTT requires binary frames, received opcode {}", other),
+                    ))
+                }
+            }
+        }
+        let len = buf.len().min(self.pending.len());
+        buf[..len].copy_from_slice(&self.pending[..len]);
+        self.pending.drain(..len);
+        Ok(len)
+    }
+}
+
+impl Write for WsStream {
+    fn write(&mut self, buf: &[u8]) -> IoResult<usize> {
+        self.stream.write_all(&encode_frame(OPCODE_BINARY, buf))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> IoResult<()> {
+        self.stream.flush()
+    }
+}
+
+/// build the `101 Switching Protocols` response for an upgrade request
+pub fn accept_response(request: &str, path: &str) -> Result<String, String> {
+    let mut lines = request.split("\r\n");
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    if parts.next() != Some("GET") || parts.next() != Some(path) {
+        return Err(format!("Unexpected request {}", request_line));
+    }
+    let mut key = None;
+    let mut upgrade = false;
+    let mut mqtt_protocol = false;
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            let value = value.trim();
+            match name.trim().to_ascii_lowercase().as_str() {
+                "upgrade" => upgrade = value.eq_ignore_ascii_case("websocket"),
+                "sec-websocket-key" => key = Some(value.to_owned()),
+                "sec-websocket-protocol" => {
+                    mqtt_protocol = value.split(',').any(|p| p.trim() == "mqtt")
+                }
+                _ => {}
+            }
+        }
+    }
+    if !upgrade {
+        return Err("Missing websocket upgrade".to_owned());
+    }
+    if !mqtt_protocol {
+        return Err("The mqtt subprotocol is required".to_owned());
+    }
+    let key = key.ok_or_else(|| "Missing Sec-WebSocket-Key".to_owned())?;
+    Ok(format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\nSec-WebSocket-Protocol: mqtt\r\n\r\n",
+        accept_key(&key)
+    ))
+}
+
+pub fn accept_key(key: &str) -> String {
+    let mut hasher = Sha1::new();
+    hasher.update(key.as_bytes());
+    hasher.update(ACCEPT_GUID.as_bytes());
+    base64::encode(hasher.finalize())
+}
+
+/// read a single frame of a client and unmask its payload. Clients have
+/// to mask every frame (RFC 6455 section 5.1), unmasked frames are an error
+pub fn read_frame<R: Read>(reader: &mut R, max_size: usize) -> IoResult<Frame> {
+    let mut header = [0u8; 2];
+    reader.read_exact(&mut header)?;
+    let opcode = header[0] & 0x0F;
+    if header[1] & 0x80 == 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Received an unmasked frame from the client",
+        ));
+    }
+    let len = match header[1] & 0x7F {
+        126 => {
+            let mut len = [0u8; 2];
+            reader.read_exact(&mut len)?;
+            u16::from_be_bytes(len) as u64
+        }
+        127 => {
+            let mut len = [0u8; 8];
+            reader.read_exact(&mut len)?;
+            u64::from_be_bytes(len)
+        }
+        len => len as u64,
+    };
+    if len > max_size as u64 {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("Frame of {} bytes exceeds the limit", len),
+        ));
+    }
+    let mut mask = [0u8; 4];
+    reader.read_exact(&mut mask)?;
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload)?;
+    for (i, byte) in payload.iter_mut().enumerate() {
+        *byte ^= mask[i % 4];
+    }
+    Ok(Frame { opcode, payload })
+}
+
+/// encode a final, unmasked frame as it is sent by a server
+pub fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn accept_key_from_rfc() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn upgrade_request() {
+        let request = "GET /mqtt HTTP/1.1\r\nHost: localhost\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Protocol: mqttv3.1, mqtt\r\nSec-WebSocket-Version: 13\r\n\r\n";
+        let response = accept_response(request, "/mqtt").unwrap();
+        assert!(response.starts_with("HTTP/1.1 101"));
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        assert!(response.contains("Sec-WebSocket-Protocol: mqtt\r\n"));
+
+        assert!(accept_response(request, "/other").is_err());
+        let without_protocol = request.replace("mqttv3.1, mqtt", "chat");
+        assert!(accept_response(&without_protocol, "/mqtt").is_err());
+    }
+
+    #[test]
+    fn masked_frame() {
+        // masked "Hello" from RFC 6455 section 5.7
+        let mut frame = Cursor::new(vec![
+            0x81, 0x85, 0x37, 0xfa, 0x21, 0x3d, 0x7f, 0x9f, 0x4d, 0x51, 0x58,
+        ]);
+        assert_eq!(
+            read_frame(&mut frame, 1024).unwrap(),
+            Frame {
+                opcode: 0x1,
+                payload: b"Hello".to_vec()
+            }
+        );
+    }
+
+    #[test]
+    fn unmasked_frame() {
+        let frame = encode_frame(OPCODE_BINARY, b"Hello");
+        let error = read_frame(&mut Cursor::new(frame), 1024).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    /// mask a frame sent by the server as a client would send it
+    fn mask(mut frame: Vec<u8>) -> Vec<u8> {
+        let key = [0x37, 0xfa, 0x21, 0x3d];
+        let header_len = match frame[1] {
+            126 => 4,
+            127 => 10,
+            _ => 2,
+        };
+        frame[1] |= 0x80;
+        for (i, byte) in frame[header_len..].iter_mut().enumerate() {
+            *byte ^= key[i % 4];
+        }
+        frame.splice(header_len..header_len, key);
+        frame
+    }
+
+    #[test]
+    fn frame_roundtrip() {
+        for len in [0, 125, 126, 70_000] {
+            let payload = vec![7u8; len];
+            let encoded = mask(encode_frame(OPCODE_BINARY, &payload));
+            let frame = read_frame(&mut Cursor::new(encoded), 70_000).unwrap();
+            assert_eq!(frame.opcode, OPCODE_BINARY);
+            assert_eq!(frame.payload, payload);
+        }
+    }
+
+    #[test]
+    fn frame_size_limit() {
+        let encoded = mask(encode_frame(OPCODE_BINARY, &[7u8; 126]));
+        let error = read_frame(&mut Cursor::new(encoded), 125).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+}
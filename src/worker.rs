@@ -1,13 +1,43 @@
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
 use crate::client::WriterProcessHandler;
 use crate::coordinator::{
-    Cleanup, CoordinatorProcess, CoordinatorProcessHandler, PollResponse, Release, RetryLater, Sent,
+    Cleanup, CoordinatorProcess, CoordinatorProcessHandler, Downgraded, PollResponse, Release,
+    RetryLater, Sent, Undeliverable,
 };
 use crate::metrics::{MetricsProcess, MetricsProcessHandler};
-use crate::structure::{PublishContext, PublishJob, Receiver, WriterRef};
+use crate::structure::{PublishContext, PublishJob, Queue, Receiver, WriterRef};
 use lunatic::{process::ProcessRef, sleep, Mailbox, Process};
-use mqtt_packet_3_5::{ConfirmationPacket, MqttPacket, PacketType, PubcompPubrelCode};
+use mqtt_packet_3_5::{
+    ConfirmationPacket, MqttPacket, PacketType, PubcompPubrelCode, PublishPacket,
+};
+
+/// the packet as it is sent to one subscriber. It is downgraded to the QoS the
+/// subscription was granted, the retain flag is only kept for subscriptions with
+/// Retain As Published and the identifiers of all matching subscriptions of the
+/// subscriber are attached
+fn packet_for(packet: &PublishPacket, queue: &Queue, subscriber: &WriterRef) -> PublishPacket {
+    let mut packet = packet.clone();
+    let options = queue.options_of(subscriber);
+    if options.qos < packet.qos {
+        packet.qos = options.qos;
+        if packet.qos == 0 {
+            packet.message_id = None;
+        }
+    }
+    packet.retain = packet.retain && options.retain_as_published;
+    let identifiers = queue.identifiers_of(subscriber);
+    if let Some(properties) = packet.properties.as_mut() {
+        properties.subscription_identifier = None;
+    }
+    if !identifiers.is_empty() {
+        packet
+            .properties
+            .get_or_insert_with(Default::default)
+            .subscription_identifier = Some(identifiers);
+    }
+    packet
+}
 
 fn process_publish(
     coordinator: ProcessRef<CoordinatorProcess>,
@@ -16,8 +46,7 @@ fn process_publish(
     ctx: PublishContext,
 ) {
     let message_uuid = publish.message.message_uuid;
-    let packet = ctx.packet;
-    let message_qos = packet.qos;
+    let packet = ctx.forwarded_packet(SystemTime::now());
     lunatic_log::debug!(
         "[Worker->Publish] Received Publish {}, {:?}",
         message_uuid,
@@ -27,6 +56,18 @@ fn process_publish(
     let mut inactive_subs: Vec<WriterRef> = vec![];
     let mut sent_to: Vec<Receiver> = vec![];
     for sub in publish.queue.subscribers.iter() {
+        let options = publish.queue.options_of(sub);
+        if options.no_local && sub.client_id == ctx.sender.client_id {
+            continue;
+        }
+        let mut forwarded = packet_for(&packet, &publish.queue, sub);
+        // a retained message for a new subscription keeps its retain flag
+        forwarded.retain =
+            forwarded.retain || ctx.copy_for.as_ref().is_some_and(|target| target.retained);
+        if sub.exceeds_maximum_packet_size(&forwarded) {
+            continue;
+        }
+        let received_qos = forwarded.qos;
         lunatic_log::debug!(
             "[Worker->Publish] Sending Publish to client {}, {:?}",
             message_uuid,
@@ -37,7 +78,7 @@ fn process_publish(
             .process
             .as_ref()
             .unwrap()
-            .write_packet(MqttPacket::Publish(packet.clone()));
+            .write_packet(MqttPacket::Publish(forwarded));
         message_sent = message_sent || result;
         // take note of all inactive subscribers and discard them
         if !result {
@@ -45,14 +86,53 @@ fn process_publish(
         } else {
             sent_to.push(Receiver {
                 writer: sub.clone(),
-                received_qos: packet.qos,
+                received_qos,
             });
             // short-circuit for qos 2 because it needs to be sent only once
-            if message_qos == 2 {
+            if received_qos == 2 {
                 break;
             }
         }
     }
+    // every group receives the message once, members that are offline are
+    // skipped in favour of the next one the coordinator selected
+    for group in publish.queue.groups.iter() {
+        if sent_to.iter().any(|receiver| receiver.received_qos == 2) {
+            break;
+        }
+        for member in group.members.iter() {
+            let forwarded = packet_for(&packet, &publish.queue, member);
+            if member.exceeds_maximum_packet_size(&forwarded) {
+                continue;
+            }
+            let received_qos = forwarded.qos;
+            let result = member
+                .process
+                .as_ref()
+                .unwrap()
+                .write_packet(MqttPacket::Publish(forwarded));
+            if !result {
+                lunatic_log::debug!(
+                    "[Worker->Publish] Member {} of group {} is offline",
+                    member.client_id,
+                    group.name
+                );
+                inactive_subs.push(member.clone());
+                continue;
+            }
+            message_sent = true;
+            sent_to.push(Receiver {
+                writer: member.clone(),
+                received_qos,
+            });
+            break;
+        }
+    }
+    // every subscriber was skipped, retrying would not change that
+    if !message_sent && packet.qos > 0 && inactive_subs.is_empty() {
+        coordinator.drop_undeliverable(Undeliverable(message_uuid));
+        return;
+    }
     if !message_sent && packet.qos > 0 {
         lunatic_log::error!("Failed to send message {:?} | {:?}", packet, publish.queue);
         // unlock message in coordinator because apparently there are not active
@@ -61,6 +141,16 @@ fn process_publish(
         sleep(Duration::from_millis(1000));
         return;
     }
+    // subscribers that were granted a lower QoS do not take part in the flow
+    // of the publisher, without a full QoS receiver the broker completes it
+    if packet.qos > 0
+        && !sent_to
+            .iter()
+            .any(|receiver| receiver.received_qos == packet.qos)
+    {
+        coordinator.complete_downgraded(Downgraded(message_uuid, inactive_subs));
+        return;
+    }
     lunatic_log::debug!("[Worker-Publish] Successfully sent message");
     if let (Ok(duration), 0) = (ctx.started_at.elapsed(), packet.qos) {
         metrics_process.track_delivery_time(0, duration.as_millis() as f64);
@@ -110,20 +200,14 @@ pub fn worker_process() {
                 }
                 PollResponse::Confirmation(confirm, _ctx) => {
                     lunatic_log::debug!("[Worker->Confirmation] received confirmation for message {} to process {:?}", confirm.message_id, confirm.packet);
-                    // this is safe because the coordinator will never allow a None confirmation to
-                    // be processed by a worker
+                    // the coordinator only hands out confirmations for reachable publishers
                     // wrap the packet correctly
                     let (qos, wrapped_packet) = if confirm.packet.cmd == PacketType::Puback {
                         (1, MqttPacket::Puback(confirm.packet))
                     } else {
                         (2, MqttPacket::Pubrec(confirm.packet))
                     };
-                    if confirm
-                        .publisher
-                        .process
-                        .unwrap()
-                        .write_packet(wrapped_packet)
-                    {
+                    if confirm.publisher.acknowledge(wrapped_packet) {
                         if let Ok(duration) = confirm.started_at.elapsed() {
                             metrics_process.track_delivery_time(qos, duration.as_millis() as f64);
                         }
@@ -145,12 +229,10 @@ pub fn worker_process() {
                         complete.message_id,
                         complete.publisher
                     );
-                    // this is safe because the coordinator will never allow a None process to be processed
+                    // the coordinator only hands out jobs for reachable publishers
                     if complete
                         .publisher
-                        .process
-                        .unwrap()
-                        .write_packet(MqttPacket::Pubcomp(ConfirmationPacket {
+                        .acknowledge(MqttPacket::Pubcomp(ConfirmationPacket {
                             cmd: PacketType::Pubcomp,
                             message_id: complete.message_id,
                             properties: None,
@@ -177,7 +259,7 @@ pub fn worker_process() {
                         release.message_id, ctx.sender
                     );
                     // send pubrel to receiver
-                    for rec in ctx.receivers.iter() {
+                    for rec in ctx.receivers.iter().filter(|rec| rec.received_qos == 2) {
                         if let Some(w) = &rec.writer.process {
                             if w.write_packet(MqttPacket::Pubrel(ConfirmationPacket {
                                 cmd: PacketType::Pubrel,
@@ -190,12 +272,10 @@ pub fn worker_process() {
                             }
                         }
                     }
-                    // this is safe because the coordinator will never allow a None process to be processed
+                    // the coordinator only hands out jobs for reachable publishers
                     if ctx
                         .sender
-                        .process
-                        .unwrap()
-                        .write_packet(MqttPacket::Pubcomp(ConfirmationPacket {
+                        .acknowledge(MqttPacket::Pubcomp(ConfirmationPacket {
                             cmd: PacketType::Pubcomp,
                             message_id: release.message_id,
                             properties: None,
@@ -214,3 +294,52 @@ pub fn worker_process() {
         }
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structure::SubscriptionOptions;
+    use std::collections::HashMap;
+    use uuid::Uuid;
+
+    #[test]
+    fn downgrade_to_granted_qos() {
+        let subscriber = WriterRef {
+            client_id: "subscriber".to_owned(),
+            session_id: Uuid::new_v4(),
+            ..WriterRef::broker()
+        };
+        let queue = |qos| Queue {
+            id: 0,
+            name: "a/b".to_owned(),
+            subscribers: vec![subscriber.clone()],
+            groups: vec![],
+            options: HashMap::from([(
+                subscriber.session_id,
+                SubscriptionOptions {
+                    qos,
+                    ..Default::default()
+                },
+            )]),
+            identifiers: HashMap::new(),
+        };
+        let packet = |qos| PublishPacket {
+            dup: false,
+            qos,
+            retain: true,
+            topic: "a/b".to_owned(),
+            message_id: Some(7),
+            payload: b"21".to_vec(),
+            properties: None,
+        };
+
+        let forwarded = packet_for(&packet(2), &queue(1), &subscriber);
+        assert_eq!((forwarded.qos, forwarded.message_id), (1, Some(7)));
+        assert!(!forwarded.retain);
+        let forwarded = packet_for(&packet(1), &queue(0), &subscriber);
+        assert_eq!((forwarded.qos, forwarded.message_id), (0, None));
+        // a message is never upgraded
+        let forwarded = packet_for(&packet(1), &queue(2), &subscriber);
+        assert_eq!(forwarded.qos, 1);
+    }
+}